@@ -1,4 +1,12 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::CStr;
+use std::hash::Hasher;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::raw::c_char;
 
 #[link(name = "our_code")]
 extern "C" {
@@ -7,102 +15,1242 @@ extern "C" {
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
     fn our_code_starts_here(input: i64, starting_addr: *mut u8) -> i64;
+
+    // function table emitted by the compiler, indexed by the id stored in each snek frame
+    #[link_name = "\x01snek_function_count"]
+    static SNEK_FUNCTION_COUNT: u64;
+    #[link_name = "\x01snek_function_names"]
+    static SNEK_FUNCTION_NAMES: *const c_char;
+
+    // error sites emitted by the compiler, each one is (file, line, column, operation)
+    #[link_name = "\x01snek_site_count"]
+    static SNEK_SITE_COUNT: u64;
+    #[link_name = "\x01snek_sites"]
+    static SNEK_SITES: [u64; 4];
+
+    // struct types emitted by the compiler, each one is (name, field count, field names)
+    #[link_name = "\x01snek_struct_count"]
+    static SNEK_STRUCT_COUNT: u64;
+    #[link_name = "\x01snek_structs"]
+    static SNEK_STRUCTS: [u64; 3];
+}
+
+// rbp of the innermost snek frame, stored by the generated code before it calls
+// into the runtime or raises an error
+#[export_name = "\x01snek_frame"]
+pub static mut SNEK_FRAME: *const u64 = std::ptr::null();
+
+// site id of the operation that called into the runtime
+#[export_name = "\x01snek_site"]
+pub static mut SNEK_SITE: i64 = -1;
+
+// end of the snek heap, allocations in the runtime and the generated code must not go past it
+#[export_name = "\x01snek_heap_end"]
+pub static mut SNEK_HEAP_END: *const u64 = std::ptr::null();
+
+// the command line arguments as a tuple, read by the generated code for args
+#[export_name = "\x01snek_args"]
+pub static mut SNEK_ARGS: i64 = 1;
+
+// header byte of heap objects that are not tuples, a tuple header is always even (length << 1)
+const BIGNUM_TAG: i64 = 3;
+const FLOAT_TAG: i64 = 5;
+const HASH_TAG: i64 = 7;
+const FILE_TAG: i64 = 9;
+// a struct header also has the struct id in bits 8 to 31 and the field count above
+const STRUCT_TAG: i64 = 11;
+// a function value is a static object: header, code address, arity and function id
+const FUNCTION_TAG: i64 = 13;
+
+// the type a failing operation expected, 99 and 100 get one of these as extra
+const EXPECT_NUMBER: i64 = 1;
+const EXPECT_TUPLE: i64 = 2;
+const EXPECT_BOOL: i64 = 3;
+const EXPECT_FINITE: i64 = 4;
+const EXPECT_HASH: i64 = 5;
+const EXPECT_FILE: i64 = 6;
+const EXPECT_TEXT: i64 = 7;
+const EXPECT_STRUCT: i64 = 8;
+const EXPECT_FUNCTION: i64 = 9;
+
+// operation codes used by the compiler when calling snek_arith
+const ARITH_PLUS: i64 = 0;
+const ARITH_MINUS: i64 = 1;
+const ARITH_TIMES: i64 = 2;
+
+// operation codes used by the compiler when calling snek_compare
+const CMP_LESS: i64 = 0;
+const CMP_LESS_EQUAL: i64 = 1;
+const CMP_GREATER: i64 = 2;
+const CMP_GREATER_EQUAL: i64 = 3;
+
+// a value allocated by the runtime, returned in rax and rdx so the
+// generated code can move its heap pointer past the new object
+#[repr(C)]
+pub struct SnekAlloc {
+    val: i64,
+    heap: *mut u64,
 }
 
+// site is the id of the failing operation in the site table, value the offending
+// value and extra anything else the message needs (the expected type for 99 and 100,
+// the length of the tuple for 102)
 #[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
-    // TODO: print error message according to writeup
-    if errcode == 99 {
-        eprintln!("invalid argument, the type of argument is wrong");
+pub extern "C" fn snek_error(errcode: i64, site: i64, value: i64, extra: i64) {
+    let (location, op) = match site_info(site) {
+        Some(info) => info,
+        None => (String::new(), "an operation".to_string()),
+    };
+    let message = if errcode == 99 || errcode == 100 {
+        let got = snek_str(value);
+        format!("invalid argument to {op}: expected {}, got {got}", expected_name(extra))
     } else if errcode == 101 {
-        eprintln!("overflow");
-    } else if errcode == 100 {
-        eprintln!("value is not a tuple, can't use index to look up");
+        format!("overflow in {op}")
+    } else if errcode == 113 {
+        let arity = unsafe { *((value - 1) as *const i64).add(2) };
+        format!("{} takes {arity} arguments, {op} called it with {extra}", snek_str(value))
+    } else if errcode == 112 {
+        format!("invalid argument to {op}: expected a {}, got {}", struct_info(extra).0, snek_str(value))
+    } else if errcode == 103 {
+        format!("invalid length {} for {op}, length must not be negative", value >> 1)
+    } else if errcode == 104 {
+        format!("out of memory in {op}, {} words needed", value >> 1)
+    } else if errcode == 106 {
+        format!("key {} not found in {op}", snek_str(value))
+    } else if errcode == 111 {
+        format!("no arm of {op} matches {}", snek_str(value))
+    } else if errcode == 107 {
+        format!("can't open {} in {op}: {}", text_str(value), io_error_message())
+    } else if errcode == 108 {
+        format!("{op} on a closed file {}", snek_str(value))
+    } else if errcode == 109 {
+        let mode = if extra == 1 { "writing" } else { "reading" };
+        format!("{op} on {}, which is not open for {mode}", snek_str(value))
+    } else if errcode == 110 {
+        format!("{op} on {} failed: {}", snek_str(value), io_error_message())
+    } else if errcode == 102 && value < 0 {
+        format!("negative index {} for tuple of length {}, indices start at 0", value >> 1, extra >> 1)
     } else if errcode == 102 {
-        eprintln!("index out of bound");
-    }else {
-        eprintln!("an error ocurred {errcode}");
-    }
+        format!("index {} out of bounds for tuple of length {}", value >> 1, extra >> 1)
+    } else {
+        format!("an error ocurred {errcode}")
+    };
+    eprintln!("{location}{message}");
+    print_backtrace();
     std::process::exit(1);
 }
 
+// how the error messages describe an EXPECT_* type
+fn expected_name(expected: i64) -> &'static str {
+    match expected {
+        EXPECT_NUMBER => "a number",
+        EXPECT_TUPLE => "a tuple",
+        EXPECT_BOOL => "a boolean",
+        EXPECT_FINITE => "a finite number",
+        EXPECT_HASH => "a hash map",
+        EXPECT_FILE => "a file",
+        EXPECT_TEXT => "a tuple of character codes",
+        EXPECT_STRUCT => "a struct",
+        EXPECT_FUNCTION => "a function",
+        _ => "another type",
+    }
+}
+
+// the expected type matching a value, for errors between two operands
+fn type_of(val: i64) -> i64 {
+    if Number::from_val(val).is_some() {
+        EXPECT_NUMBER
+    } else if val & 3 == 3 {
+        EXPECT_BOOL
+    } else if heap_tag(val) == Some(HASH_TAG) {
+        EXPECT_HASH
+    } else if heap_tag(val) == Some(FILE_TAG) {
+        EXPECT_FILE
+    } else if heap_tag(val) == Some(STRUCT_TAG) {
+        EXPECT_STRUCT
+    } else if heap_tag(val) == Some(FUNCTION_TAG) {
+        EXPECT_FUNCTION
+    } else {
+        EXPECT_TUPLE
+    }
+}
+
+// raise an error from inside the runtime, blaming the site the generated code called it from
+fn runtime_error(errcode: i64, value: i64, extra: i64) -> ! {
+    snek_error(errcode, unsafe { SNEK_SITE }, value, extra);
+    unreachable!()
+}
+
+// "file:line:col: " and the name of the operation for a site id
+fn site_info(site: i64) -> Option<(String, String)> {
+    unsafe {
+        if site < 0 || site as u64 >= SNEK_SITE_COUNT {
+            return None;
+        }
+        let entry = std::ptr::addr_of!(SNEK_SITES).add(site as usize);
+        let [file, line, col, op] = *entry;
+        let op = CStr::from_ptr(op as *const c_char).to_string_lossy().to_string();
+        let file = CStr::from_ptr(file as *const c_char).to_string_lossy();
+        if line == 0 {
+            return Some((String::new(), op));
+        }
+        Some((format!("{file}:{line}:{col}: "), op))
+    }
+}
+
+fn function_name(id: u64) -> String {
+    unsafe {
+        if id >= SNEK_FUNCTION_COUNT {
+            return "function".to_string();
+        }
+        let names = std::ptr::addr_of!(SNEK_FUNCTION_NAMES);
+        CStr::from_ptr(*names.add(id as usize)).to_string_lossy().to_string()
+    }
+}
+
+// walk the chain of saved frame pointers, each snek frame keeps its function id at [rbp - 8]
+fn print_backtrace() {
+    let mut frame = unsafe { SNEK_FRAME };
+    if frame.is_null() {
+        return;
+    }
+    unsafe {
+        loop {
+            let id = *frame.sub(1);
+            if id >= SNEK_FUNCTION_COUNT {
+                break;
+            }
+            eprintln!("  at {}", function_name(id));
+            if id == 0 {
+                break;
+            }
+            frame = *frame as *const u64;
+        }
+    }
+}
+
+// name and field names of the struct type with id
+fn struct_info(id: i64) -> (String, Vec<String>) {
+    unsafe {
+        if id < 0 || id as u64 >= SNEK_STRUCT_COUNT {
+            return ("struct".to_string(), Vec::new());
+        }
+        let [name, count, fields] = *std::ptr::addr_of!(SNEK_STRUCTS).add(id as usize);
+        let name = CStr::from_ptr(name as *const c_char).to_string_lossy().to_string();
+        let fields = (0..count as usize)
+            .map(|i| {
+                let field = *(fields as *const *const c_char).add(i);
+                CStr::from_ptr(field).to_string_lossy().to_string()
+            })
+            .collect();
+        (name, fields)
+    }
+}
+
+// structural equality. Pairs of tuples already being compared are assumed equal,
+// so two cyclic structures are equal when no path through them finds a difference.
+// The pairs still to compare live on an explicit stack, nesting depth doesn't matter
 #[export_name = "\x01snek_equal"]
 fn snek_equal(val1 : i64, val2: i64) -> i64 {
-    let mut seen1 = Vec::<i64>::new();
-    let mut seen2 = Vec::<i64>::new();
-    let str1 = snek_str(val1, &mut seen1);
-    let str2 = snek_str(val2, &mut seen1);
-    if (str1 == str2) {
-        return 7;
+    let mut visited = HashSet::<(i64, i64)>::new();
+    let mut pending = vec![(val1, val2)];
+    while let Some((v1, v2)) = pending.pop() {
+        if v1 == v2 {
+            continue;
+        }
+        match (Number::from_val(v1), Number::from_val(v2)) {
+            (Some(Number::Int(n1)), Some(Number::Int(n2))) if n1 == n2 => continue,
+            (Some(Number::Float(f1)), Some(Number::Float(f2))) if f1 == f2 => continue,
+            (None, None) => {}
+            _ => return 3,
+        }
+        // what is left are distinct booleans, nil, hash maps (equal by identity), tuples and structs
+        let (Some(len), Some(_)) = (field_count(v1), field_count(v2)) else {
+            return 3;
+        };
+        if !visited.insert((v1, v2)) {
+            continue;
+        }
+        // the same header means the same kind of value, struct type and length
+        let (addr1, addr2) = ((v1 - 1) as *const i64, (v2 - 1) as *const i64);
+        if unsafe { *addr1 != *addr2 } {
+            return 3;
+        }
+        for i in (1..=len).rev() {
+            pending.push(unsafe { (*addr1.add(i), *addr2.add(i)) });
+        }
+    }
+    7
+}
+
+fn is_tuple(val: i64) -> bool {
+    val & 3 == 1 && val != 1 && unsafe { *((val - 1) as *const i64) } & 1 == 0
+}
+
+// number of elements of a tuple or fields of a struct, they follow the header
+fn field_count(val: i64) -> Option<usize> {
+    if is_tuple(val) {
+        Some((unsafe { *((val - 1) as *const i64) } >> 1) as usize)
+    } else if heap_tag(val) == Some(STRUCT_TAG) {
+        Some((unsafe { *((val - 1) as *const i64) } >> 32) as usize)
     } else {
-        return 3;
+        None
     }
 }
 
 
 
+// (print a b ...) gets a and a tuple of the other arguments, or nil when there are none.
+// They are printed on one line separated by spaces, the last one is the result
 #[export_name = "\x01snek_print"]
-fn snek_print(val : i64) -> i64 {
-    let mut seen = Vec::<i64>::new();
-    println!("{}", snek_str(val, &mut seen));
-    return val;
+fn snek_print(val : i64, rest: i64) -> i64 {
+    let mut vals = vec![val];
+    vals.extend(children(rest));
+    println!("{}", vals.iter().map(|&v| snek_str(v)).collect::<Vec<String>>().join(" "));
+    return *vals.last().unwrap();
+}
+
+// a hash map lives in the runtime, its heap object is a header and a pointer to this.
+// Entries keep insertion order, index maps the hash of a key to its entries
+struct SnekHash {
+    entries: Vec<(i64, i64)>,
+    index: HashMap<u64, Vec<usize>>,
+}
+
+impl SnekHash {
+    fn find(&self, key: i64, hash: u64) -> Option<usize> {
+        let candidates = self.index.get(&hash)?;
+        candidates.iter().copied().find(|&i| snek_equal(self.entries[i].0, key) == 7)
+    }
+}
+
+// structural hash, equal values (by snek_equal) hash the same. Tuples are only
+// hashed a few levels deep so cycles terminate, hash maps by identity
+fn hash_value(val: i64, depth: u32, hasher: &mut DefaultHasher) {
+    match Number::from_val(val) {
+        Some(Number::Int(n)) => {
+            hasher.write_u8(n.neg as u8);
+            for limb in &n.mag {
+                hasher.write_u64(*limb);
+            }
+            return;
+        }
+        Some(Number::Float(f)) => {
+            // -0.0 == 0.0, so they must hash the same
+            hasher.write_u64((f + 0.0).to_bits());
+            return;
+        }
+        None => {}
+    }
+    let Some(len) = field_count(val) else {
+        hasher.write_i64(val);
+        return;
+    };
+    let addr = (val - 1) as *const i64;
+    hasher.write_i64(unsafe { *addr });
+    if depth == 0 {
+        return;
+    }
+    for i in 1..=len {
+        hash_value(unsafe { *addr.add(i) }, depth - 1, hasher);
+    }
+}
+
+fn hash_key(key: i64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_value(key, 4, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_arg(val: i64) -> &'static mut SnekHash {
+    if heap_tag(val) != Some(HASH_TAG) {
+        runtime_error(99, val, EXPECT_HASH);
+    }
+    unsafe { &mut *(*((val - 1) as *const u64).add(1) as *mut SnekHash) }
+}
+
+#[export_name = "\x01snek_hash_new"]
+pub extern "C" fn snek_hash_new(heap: *mut u64) -> SnekAlloc {
+    heap_check(heap, 2);
+    let hash = Box::new(SnekHash { entries: Vec::new(), index: HashMap::new() });
+    unsafe {
+        *heap = (1 << 32) | HASH_TAG as u64;
+        *heap.add(1) = Box::into_raw(hash) as u64;
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(2) }
+    }
+}
+
+#[export_name = "\x01snek_hash_get"]
+pub extern "C" fn snek_hash_get(val: i64, key: i64, heap: *mut u64) -> SnekAlloc {
+    let hash = hash_arg(val);
+    match hash.find(key, hash_key(key)) {
+        Some(i) => SnekAlloc { val: hash.entries[i].1, heap },
+        None => runtime_error(106, key, 0),
+    }
+}
+
+#[export_name = "\x01snek_hash_set"]
+pub extern "C" fn snek_hash_set(val: i64, key: i64, value: i64, heap: *mut u64) -> SnekAlloc {
+    let hash = hash_arg(val);
+    let code = hash_key(key);
+    match hash.find(key, code) {
+        Some(i) => hash.entries[i].1 = value,
+        None => {
+            hash.index.entry(code).or_default().push(hash.entries.len());
+            hash.entries.push((key, value));
+        }
+    }
+    SnekAlloc { val, heap }
+}
+
+#[export_name = "\x01snek_hash_has"]
+pub extern "C" fn snek_hash_has(val: i64, key: i64, heap: *mut u64) -> SnekAlloc {
+    let hash = hash_arg(val);
+    let found = hash.find(key, hash_key(key)).is_some();
+    SnekAlloc { val: if found { 7 } else { 3 }, heap }
+}
+
+// the keys in insertion order, as a tuple
+#[export_name = "\x01snek_hash_keys"]
+pub extern "C" fn snek_hash_keys(val: i64, heap: *mut u64) -> SnekAlloc {
+    let hash = hash_arg(val);
+    let len = hash.entries.len();
+    heap_check(heap, len + 1);
+    unsafe {
+        *heap = (len as u64) << 1;
+        for (i, (key, _)) in hash.entries.iter().enumerate() {
+            *heap.add(i + 1) = *key as u64;
+        }
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(len + 1) }
+    }
+}
+
+// print without a newline, flushed so prompts show up before reading
+#[export_name = "\x01snek_display"]
+fn snek_display(val : i64) -> i64 {
+    print!("{}", snek_str(val));
+    std::io::stdout().flush().unwrap();
+    val
+}
+
+#[export_name = "\x01snek_newline"]
+fn snek_newline() -> i64 {
+    println!();
+    1
+}
+
+// the next line of stdin without its line ending, false at the end of input
+fn read_stdin_line() -> Option<String> {
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+        Err(err) => {
+            eprintln!("error reading stdin: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// snek has no strings, a line is a tuple of character codes
+#[export_name = "\x01snek_read_line"]
+fn snek_read_line(heap: *mut u64) -> SnekAlloc {
+    let line = match read_stdin_line() {
+        Some(line) => line,
+        None => return SnekAlloc { val: 3, heap },
+    };
+    text_val(&line, heap)
+}
+
+// a line of stdin parsed like input, false at the end of input
+#[export_name = "\x01snek_read_value"]
+fn snek_read_value(heap: *mut u64) -> SnekAlloc {
+    let line = match read_stdin_line() {
+        Some(line) => line,
+        None => return SnekAlloc { val: 3, heap },
+    };
+    match parse_value(&line, heap) {
+        Ok(res) => res,
+        Err(message) => {
+            eprintln!("invalid value {line:?} for read-value: {message}");
+            print_backtrace();
+            std::process::exit(1);
+        }
+    }
+}
+
+// a file handle is a header and a pointer to this, the value stays valid after
+// close-file so using it afterwards is an error rather than a crash
+struct SnekFile {
+    path: String,
+    state: FileState,
+}
+
+enum FileState {
+    Reading(BufReader<File>),
+    Writing(BufWriter<File>),
+    Closed,
+}
+
+// the operating system's message for the last failed file operation
+static mut SNEK_IO_ERROR: String = String::new();
+
+fn io_failure(errcode: i64, value: i64, err: std::io::Error) -> ! {
+    unsafe {
+        SNEK_IO_ERROR = err.to_string();
+    }
+    runtime_error(errcode, value, 0)
+}
+
+fn io_error_message() -> String {
+    unsafe { (*std::ptr::addr_of!(SNEK_IO_ERROR)).clone() }
+}
+
+// text is a tuple of character codes, like the lines from read-line
+fn text_arg(val: i64) -> String {
+    if !is_tuple(val) {
+        runtime_error(99, val, EXPECT_TEXT);
+    }
+    children(val)
+        .iter()
+        .map(|&code| match char::from_u32((code >> 1) as u32) {
+            Some(c) if code & 1 == 0 && code >= 0 => c,
+            _ => runtime_error(99, val, EXPECT_TEXT),
+        })
+        .collect()
+}
+
+// text for error messages, the value itself when it isn't text
+fn text_str(val: i64) -> String {
+    let is_char = |code: i64| code & 1 == 0 && code >= 0 && char::from_u32((code >> 1) as u32).is_some();
+    let is_text = is_tuple(val) && children(val).iter().all(|&code| is_char(code));
+    if is_text { text_arg(val) } else { snek_str(val) }
+}
+
+fn text_val(text: &str, heap: *mut u64) -> SnekAlloc {
+    let codes: Vec<u32> = text.chars().map(|c| c as u32).collect();
+    heap_check(heap, codes.len() + 1);
+    unsafe {
+        *heap = (codes.len() as u64) << 1;
+        for (i, code) in codes.iter().enumerate() {
+            *heap.add(i + 1) = (*code as u64) << 1;
+        }
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(codes.len() + 1) }
+    }
+}
+
+fn file_arg(val: i64) -> &'static mut SnekFile {
+    if heap_tag(val) != Some(FILE_TAG) {
+        runtime_error(99, val, EXPECT_FILE);
+    }
+    unsafe { &mut *(*((val - 1) as *const u64).add(1) as *mut SnekFile) }
+}
+
+// (open-file path write), opens path for writing (truncating it) when write is true
+// and for reading when it is false
+#[export_name = "\x01snek_open_file"]
+pub extern "C" fn snek_open_file(path: i64, write: i64, heap: *mut u64) -> SnekAlloc {
+    let name = text_arg(path);
+    let state = match write {
+        7 => File::create(&name).map(|f| FileState::Writing(BufWriter::new(f))),
+        3 => File::open(&name).map(|f| FileState::Reading(BufReader::new(f))),
+        _ => runtime_error(99, write, EXPECT_BOOL),
+    };
+    let state = match state {
+        Ok(state) => state,
+        Err(err) => io_failure(107, path, err),
+    };
+    heap_check(heap, 2);
+    let file = Box::new(SnekFile { path: name, state });
+    unsafe {
+        *heap = (1 << 32) | FILE_TAG as u64;
+        *heap.add(1) = Box::into_raw(file) as u64;
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(2) }
+    }
+}
+
+// the next line of the file as text, false at the end of the file
+#[export_name = "\x01snek_read_file"]
+pub extern "C" fn snek_read_file(val: i64, heap: *mut u64) -> SnekAlloc {
+    let reader = match &mut file_arg(val).state {
+        FileState::Reading(reader) => reader,
+        FileState::Writing(_) => runtime_error(109, val, 0),
+        FileState::Closed => runtime_error(108, val, 0),
+    };
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => SnekAlloc { val: 3, heap },
+        Ok(_) => text_val(line.trim_end_matches(['\n', '\r']), heap),
+        Err(err) => io_failure(110, val, err),
+    }
+}
+
+// write text as a line of the file, returns the file
+#[export_name = "\x01snek_write_file"]
+pub extern "C" fn snek_write_file(val: i64, text: i64, heap: *mut u64) -> SnekAlloc {
+    let writer = match &mut file_arg(val).state {
+        FileState::Writing(writer) => writer,
+        FileState::Reading(_) => runtime_error(109, val, 1),
+        FileState::Closed => runtime_error(108, val, 0),
+    };
+    if let Err(err) = writeln!(writer, "{}", text_arg(text)) {
+        io_failure(110, val, err);
+    }
+    SnekAlloc { val, heap }
+}
+
+// flushes and closes the file, closing it twice is an error
+#[export_name = "\x01snek_close_file"]
+pub extern "C" fn snek_close_file(val: i64, heap: *mut u64) -> SnekAlloc {
+    let file = file_arg(val);
+    match std::mem::replace(&mut file.state, FileState::Closed) {
+        FileState::Writing(mut writer) => {
+            if let Err(err) = writer.flush() {
+                io_failure(110, val, err);
+            }
+        }
+        FileState::Reading(_) => {}
+        FileState::Closed => runtime_error(108, val, 0),
+    }
+    SnekAlloc { val: 7, heap }
+}
+
+// arbitrary precision integer, magnitude stored as little endian 64 bit limbs without leading zeros
+#[derive(Clone, Debug, PartialEq)]
+struct BigInt {
+    neg: bool,
+    mag: Vec<u64>,
+}
+
+impl BigInt {
+    fn from_i64(n: i64) -> BigInt {
+        let mut mag = Vec::new();
+        if n != 0 {
+            mag.push(n.unsigned_abs());
+        }
+        BigInt { neg: n < 0, mag }
+    }
+
+    // read a snek number, either a plain one or a bignum on the heap
+    fn from_val(val: i64) -> Option<BigInt> {
+        if val & 1 == 0 {
+            return Some(BigInt::from_i64(val >> 1));
+        }
+        if heap_tag(val) != Some(BIGNUM_TAG) {
+            return None;
+        }
+        let addr = (val - 1) as *const u64;
+        unsafe {
+            let size = (*addr >> 32) as usize;
+            let neg = *addr.add(1) == 1;
+            let mag = (2..=size).map(|i| *addr.add(i)).collect();
+            Some(BigInt { neg, mag })
+        }
+    }
+
+    // truncates towards zero
+    fn from_f64(f: f64) -> BigInt {
+        const LIMB: f64 = 18446744073709551616.0;
+        let mut rest = f.trunc().abs();
+        let mut mag = Vec::new();
+        while rest >= 1.0 {
+            mag.push((rest % LIMB) as u64);
+            rest = (rest / LIMB).trunc();
+        }
+        BigInt { neg: f < 0.0, mag }.trim()
+    }
+
+    fn to_f64(&self) -> f64 {
+        let abs = self.mag.iter().rev().fold(0.0, |acc, limb| acc * 18446744073709551616.0 + *limb as f64);
+        if self.neg { -abs } else { abs }
+    }
+
+    fn trim(mut self) -> BigInt {
+        while self.mag.last() == Some(&0) {
+            self.mag.pop();
+        }
+        if self.mag.is_empty() {
+            self.neg = false;
+        }
+        self
+    }
+
+    fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt { neg: self.neg, mag: mag_add(&self.mag, &other.mag) }.trim();
+        }
+        match mag_cmp(&self.mag, &other.mag) {
+            Ordering::Less => BigInt { neg: other.neg, mag: mag_sub(&other.mag, &self.mag) }.trim(),
+            _ => BigInt { neg: self.neg, mag: mag_sub(&self.mag, &other.mag) }.trim(),
+        }
+    }
+
+    fn sub(&self, other: &BigInt) -> BigInt {
+        let negated = BigInt { neg: !other.neg, mag: other.mag.clone() }.trim();
+        self.add(&negated)
+    }
+
+    fn mul(&self, other: &BigInt) -> BigInt {
+        let mut mag = vec![0u64; self.mag.len() + other.mag.len()];
+        for (i, a) in self.mag.iter().enumerate() {
+            let mut carry: u128 = 0;
+            for (j, b) in other.mag.iter().enumerate() {
+                let cur = mag[i + j] as u128 + (*a as u128) * (*b as u128) + carry;
+                mag[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            mag[i + other.mag.len()] = carry as u64;
+        }
+        BigInt { neg: self.neg != other.neg, mag }.trim()
+    }
+
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.mag, &other.mag),
+            (true, true) => mag_cmp(&other.mag, &self.mag),
+        }
+    }
+
+    fn to_string(&self) -> String {
+        if self.mag.is_empty() {
+            return "0".to_string();
+        }
+        // peel off 19 decimal digits at a time
+        const CHUNK: u128 = 10_000_000_000_000_000_000;
+        let mut mag = self.mag.clone();
+        let mut chunks = Vec::new();
+        while !mag.is_empty() {
+            let mut rem: u128 = 0;
+            for limb in mag.iter_mut().rev() {
+                let cur = (rem << 64) | *limb as u128;
+                *limb = (cur / CHUNK) as u64;
+                rem = cur % CHUNK;
+            }
+            chunks.push(rem as u64);
+            while mag.last() == Some(&0) {
+                mag.pop();
+            }
+        }
+        let mut res = if self.neg { "-".to_string() } else { String::new() };
+        res.push_str(&chunks.pop().unwrap().to_string());
+        for chunk in chunks.iter().rev() {
+            res.push_str(&format!("{:019}", chunk));
+        }
+        res
+    }
+
+    // turn the result back into a snek value, only allocating when it does not fit in 63 bits
+    fn to_val(&self, heap: *mut u64) -> SnekAlloc {
+        if self.mag.len() <= 1 {
+            let m = self.mag.first().copied().unwrap_or(0);
+            if !self.neg && m < (1 << 62) {
+                return SnekAlloc { val: (m as i64) << 1, heap };
+            }
+            if self.neg && m <= (1 << 62) {
+                return SnekAlloc { val: (m as i64).wrapping_neg() << 1, heap };
+            }
+        }
+        let size = self.mag.len() + 1;
+        heap_check(heap, size + 1);
+        unsafe {
+            *heap = ((size as u64) << 32) | BIGNUM_TAG as u64;
+            *heap.add(1) = self.neg as u64;
+            for (i, limb) in self.mag.iter().enumerate() {
+                *heap.add(2 + i) = *limb;
+            }
+            SnekAlloc { val: heap as i64 + 1, heap: heap.add(size + 1) }
+        }
+    }
+}
+
+fn mag_cmp(a: &[u64], b: &[u64]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn mag_add(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut res = Vec::new();
+    let mut carry = 0u128;
+    for i in 0..a.len().max(b.len()) {
+        let cur = *a.get(i).unwrap_or(&0) as u128 + *b.get(i).unwrap_or(&0) as u128 + carry;
+        res.push(cur as u64);
+        carry = cur >> 64;
+    }
+    res.push(carry as u64);
+    res
 }
 
+// a - b, where a >= b
+fn mag_sub(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut res = Vec::new();
+    let mut borrow = 0u64;
+    for (i, x) in a.iter().enumerate() {
+        let (cur, b1) = x.overflowing_sub(*b.get(i).unwrap_or(&0));
+        let (cur, b2) = cur.overflowing_sub(borrow);
+        res.push(cur);
+        borrow = (b1 || b2) as u64;
+    }
+    res
+}
 
-fn snek_str(val: i64, seen : &mut Vec<i64>) -> String{
+// header byte of the heap object val points to, None for anything that is not on the heap
+fn heap_tag(val: i64) -> Option<i64> {
+    if val & 3 != 1 || val == 1 {
+        return None;
+    }
+    let header = unsafe { *((val - 1) as *const i64) };
+    if header & 1 == 0 {
+        return None;
+    }
+    Some(header & 255)
+}
+
+// any snek number, integers of any size or floats
+enum Number {
+    Int(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    fn from_val(val: i64) -> Option<Number> {
+        if let Some(n) = BigInt::from_val(val) {
+            return Some(Number::Int(n));
+        }
+        if heap_tag(val) != Some(FLOAT_TAG) {
+            return None;
+        }
+        let bits = unsafe { *((val - 1) as *const u64).add(1) };
+        Some(Number::Float(f64::from_bits(bits)))
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => n.to_f64(),
+            Number::Float(f) => *f,
+        }
+    }
+}
+
+fn alloc_float(f: f64, heap: *mut u64) -> SnekAlloc {
+    heap_check(heap, 2);
+    unsafe {
+        *heap = (1 << 32) | FLOAT_TAG as u64;
+        *heap.add(1) = f.to_bits();
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(2) }
+    }
+}
+
+fn number_arg(val: i64) -> Number {
+    match Number::from_val(val) {
+        Some(n) => n,
+        None => runtime_error(99, val, EXPECT_NUMBER),
+    }
+}
+
+// slow path of +, -, * and add1/sub1, taken when an operand is a bignum or
+// a float, or the fast path overflowed. Mixing in a float gives a float
+#[export_name = "\x01snek_arith"]
+pub extern "C" fn snek_arith(op: i64, val1: i64, val2: i64, heap: *mut u64) -> SnekAlloc {
+    match (number_arg(val1), number_arg(val2)) {
+        (Number::Int(n1), Number::Int(n2)) => {
+            let res = match op {
+                ARITH_PLUS => n1.add(&n2),
+                ARITH_MINUS => n1.sub(&n2),
+                ARITH_TIMES => n1.mul(&n2),
+                _ => panic!("unknown arithmetic operation {op}"),
+            };
+            res.to_val(heap)
+        }
+        (n1, n2) => {
+            let (f1, f2) = (n1.to_f64(), n2.to_f64());
+            let res = match op {
+                ARITH_PLUS => f1 + f2,
+                ARITH_MINUS => f1 - f2,
+                ARITH_TIMES => f1 * f2,
+                _ => panic!("unknown arithmetic operation {op}"),
+            };
+            alloc_float(res, heap)
+        }
+    }
+}
+
+// None when a NaN is involved, NaN is not ordered against anything, itself included
+fn number_cmp(n1: &Number, n2: &Number) -> Option<Ordering> {
+    match (n1, n2) {
+        (Number::Int(i1), Number::Int(i2)) => Some(i1.cmp(i2)),
+        _ => n1.to_f64().partial_cmp(&n2.to_f64()),
+    }
+}
+
+// slow path of <, >, <= and >=, every comparison with NaN is false
+#[export_name = "\x01snek_compare"]
+pub extern "C" fn snek_compare(op: i64, val1: i64, val2: i64) -> i64 {
+    let res = match number_cmp(&number_arg(val1), &number_arg(val2)) {
+        None => false,
+        Some(ord) => match op {
+            CMP_LESS => ord == Ordering::Less,
+            CMP_LESS_EQUAL => ord != Ordering::Greater,
+            CMP_GREATER => ord == Ordering::Greater,
+            CMP_GREATER_EQUAL => ord != Ordering::Less,
+            _ => panic!("unknown comparison {op}"),
+        },
+    };
+    if res { 7 } else { 3 }
+}
+
+// = on anything but two plain numbers: numbers compare by value, other
+// values must have the same type and compare by identity
+#[export_name = "\x01snek_eq"]
+pub extern "C" fn snek_eq(val1: i64, val2: i64) -> i64 {
+    let res = match (Number::from_val(val1), Number::from_val(val2)) {
+        (Some(Number::Int(n1)), Some(Number::Int(n2))) => n1 == n2,
+        (Some(n1), Some(n2)) => number_cmp(&n1, &n2) == Some(Ordering::Equal),
+        (None, None) if val1 & 3 == val2 & 3 => val1 == val2,
+        (Some(_), None) => runtime_error(99, val2, EXPECT_NUMBER),
+        _ => runtime_error(99, val2, type_of(val1)),
+    };
+    if res { 7 } else { 3 }
+}
+
+#[export_name = "\x01snek_to_float"]
+pub extern "C" fn snek_to_float(val: i64, heap: *mut u64) -> SnekAlloc {
+    alloc_float(number_arg(val).to_f64(), heap)
+}
+
+// truncates floats towards zero, integers are returned as they are
+#[export_name = "\x01snek_to_int"]
+pub extern "C" fn snek_to_int(val: i64, heap: *mut u64) -> SnekAlloc {
+    match number_arg(val) {
+        Number::Int(_) => SnekAlloc { val, heap },
+        Number::Float(f) if f.is_finite() => BigInt::from_f64(f).to_val(heap),
+        Number::Float(_) => runtime_error(99, val, EXPECT_FINITE),
+    }
+}
+
+// raise an error unless the snek heap has room for words more words
+fn heap_check(heap: *mut u64, words: usize) {
+    let free = unsafe { SNEK_HEAP_END.offset_from(heap) } as usize;
+    if words > free {
+        runtime_error(104, (words as i64) << 1, 0);
+    }
+}
+
+// (make-vector n init), a tuple of n elements all set to init
+#[export_name = "\x01snek_make_vector"]
+pub extern "C" fn snek_make_vector(len: i64, init: i64, heap: *mut u64) -> SnekAlloc {
+    if len & 1 != 0 {
+        runtime_error(99, len, EXPECT_NUMBER);
+    }
+    if len < 0 {
+        runtime_error(103, len, 0);
+    }
+    let words = (len >> 1) as usize + 1;
+    heap_check(heap, words);
+    unsafe {
+        *heap = len as u64;
+        for i in 1..words {
+            *heap.add(i) = init as u64;
+        }
+        SnekAlloc { val: heap as i64 + 1, heap: heap.add(words) }
+    }
+}
+
+// print state for one value: the pre-order numbers of the tuples and hash maps a back
+// reference points to, the number of the next one, and the ones being printed right
+// now with their datum label
+struct Printer {
+    labeled: HashSet<usize>,
+    path: Vec<(i64, Option<usize>)>,
+    next: usize,
+    next_label: usize,
+}
+
+// the values inside a tuple, struct or hash map, nothing for other values
+fn children(val: i64) -> Vec<i64> {
+    if heap_tag(val) == Some(HASH_TAG) {
+        hash_arg(val).entries.iter().flat_map(|&(key, value)| [key, value]).collect()
+    } else if let Some(len) = field_count(val) {
+        let addr = (val - 1) as *const i64;
+        (1..=len).map(|i| unsafe { *addr.add(i) }).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+// the printer walks a value in pre-order, printing a back reference for a value that
+// is already on its path and everything else in full. This walks it the same way and
+// returns the pre-order numbers of the values a back reference points to, only those
+// get a label. Values still to visit live on an explicit stack like in snek_equal.
+// A value whose walk met no back reference can't reach a cycle, it is finished and
+// a shared copy just skips the number of values it prints
+fn find_cycles(val: i64) -> HashSet<usize> {
+    let mut labeled = HashSet::new();
+    let mut finished: HashMap<i64, usize> = HashMap::new();
+    let mut next = 0;
+    // the values being walked, with their number and whether a back reference was met below them
+    let mut path: Vec<(i64, usize, bool)> = Vec::new();
+    // Some(v) enters v, None leaves the last value of the path
+    let mut pending = vec![Some(val)];
+    while let Some(item) = pending.pop() {
+        match item {
+            Some(v) if field_count(v).is_none() && heap_tag(v) != Some(HASH_TAG) => {}
+            Some(v) if path.iter().any(|(p, _, _)| *p == v) => {
+                let (_, number, _) = path.iter().find(|(p, _, _)| *p == v).unwrap();
+                labeled.insert(*number);
+                if let Some(top) = path.last_mut() {
+                    top.2 = true;
+                }
+            }
+            Some(v) if finished.contains_key(&v) => next += finished[&v],
+            Some(v) => {
+                path.push((v, next, false));
+                next += 1;
+                pending.push(None);
+                pending.extend(children(v).into_iter().rev().map(Some));
+            }
+            None => {
+                let (v, number, met_cycle) = path.pop().unwrap();
+                match path.last_mut() {
+                    _ if !met_cycle => {
+                        finished.insert(v, next - number);
+                    }
+                    Some(top) => top.2 = true,
+                    None => {}
+                }
+            }
+        }
+    }
+    labeled
+}
+
+// cycles are printed with datum labels, #0=(1,2,#0#) is a tuple whose last element is
+// itself. Values that are only shared are printed in full every time
+fn snek_str(val: i64) -> String {
+    value_str(val, &mut Printer { labeled: find_cycles(val), path: Vec::new(), next: 0, next_label: 0 })
+}
+
+fn value_str(val: i64, printer: &mut Printer) -> String {
     if val == 7 { "true".to_string() }
     else if val == 3 { "false".to_string()  }
     else if val % 2 == 0 { format!("{}", val >> 1) }
     else if val == 1 { "nil".to_string() }
+    else if heap_tag(val) == Some(BIGNUM_TAG) { BigInt::from_val(val).unwrap().to_string() }
+    else if heap_tag(val) == Some(FLOAT_TAG) { format!("{:?}", Number::from_val(val).unwrap().to_f64()) }
+    else if heap_tag(val) == Some(FUNCTION_TAG) {
+        let id = unsafe { *((val - 1) as *const u64).add(3) };
+        format!("<function {}>", function_name(id))
+    }
+    else if heap_tag(val) == Some(FILE_TAG) {
+        let file = file_arg(val);
+        match file.state {
+            FileState::Closed => format!("<closed file {:?}>", file.path),
+            _ => format!("<file {:?}>", file.path),
+        }
+    }
     else {
-        if seen.contains(&val)  { return "(tuple <cyclic>)".to_string()}
-        seen.push(val);
-        let addr: *const i64 = (val - 1) as *const i64;
-        let mut length;
-        unsafe {
-            length = *(addr);
-        }
-        length /= 2;
-        let mut res = String::new();
-        res.push('(');
-        let mut res = '('.to_string();
-        for num in 1..= length {
-            let value;
-            let value_addr: *const i64 = (val - 1 + num * 8) as *const i64;
+        if let Some((_, label)) = printer.path.iter().find(|(v, _)| *v == val) {
+            return format!("#{}#", label.expect("back reference to a value without a label"));
+        }
+        printer.next += 1;
+        let label = if printer.labeled.contains(&(printer.next - 1)) {
+            printer.next_label += 1;
+            Some(printer.next_label - 1)
+        } else {
+            None
+        };
+        printer.path.push((val, label));
+        let items = children(val);
+        let res = if heap_tag(val) == Some(HASH_TAG) {
+            let entries: Vec<String> = items
+                .chunks(2)
+                .map(|entry| format!("{}: {}", value_str(entry[0], printer), value_str(entry[1], printer)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        } else if heap_tag(val) == Some(STRUCT_TAG) {
+            // (node val: 3 left: nil right: nil)
+            let (name, fields) = struct_info((unsafe { *((val - 1) as *const i64) } >> 8) & 0xffffff);
+            let mut res = name;
+            for (field, &item) in fields.iter().zip(&items) {
+                res.push_str(&format!(" {}: {}", field, value_str(item, printer)));
+            }
+            format!("({res})")
+        } else {
+            let elements: Vec<String> = items.iter().map(|&item| value_str(item, printer)).collect();
+            format!("({})", elements.join(","))
+        };
+        printer.path.pop();
+        match label {
+            Some(label) => format!("#{label}={res}"),
+            None => res,
+        }
+    }
+}
+
+// reads a snek value written in snek syntax: true, false, nil, numbers and
+// (tuple ...), allocating what it needs on the snek heap
+struct InputParser {
+    chars: Vec<char>,
+    pos: usize,
+    heap: *mut u64,
+}
+
+impl InputParser {
+    fn skip_space(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn token(&mut self) -> String {
+        self.skip_space();
+        let start = self.pos;
+        while self.pos < self.chars.len()
+            && !self.chars[self.pos].is_whitespace()
+            && self.chars[self.pos] != '('
+            && self.chars[self.pos] != ')'
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn alloc(&mut self, words: usize) -> Result<(), String> {
+        let free = unsafe { SNEK_HEAP_END.offset_from(self.heap) } as usize;
+        if words > free {
+            return Err("input is too large for the heap".to_string());
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<i64, String> {
+        self.skip_space();
+        if self.pos >= self.chars.len() {
+            return Err("expected a value, found the end of the input".to_string());
+        }
+        if self.chars[self.pos] == '(' {
+            self.pos += 1;
+            let head = self.token();
+            if head != "tuple" {
+                return Err(format!("expected tuple after '(', found '{head}'"));
+            }
+            let mut elements = Vec::new();
+            loop {
+                self.skip_space();
+                match self.chars.get(self.pos) {
+                    Some(')') => break,
+                    Some(_) => elements.push(self.value()?),
+                    None => return Err("missing ')' at the end of the input".to_string()),
+                }
+            }
+            self.pos += 1;
+            if elements.is_empty() {
+                return Err("a tuple needs at least one element".to_string());
+            }
+            self.alloc(elements.len() + 1)?;
             unsafe {
-                value = *(value_addr);
-            };
-            res.push_str(&snek_str(value, seen));
-            res.push(',');
+                *self.heap = (elements.len() as u64) << 1;
+                for (i, element) in elements.iter().enumerate() {
+                    *self.heap.add(i + 1) = *element as u64;
+                }
+                let val = self.heap as i64 + 1;
+                self.heap = self.heap.add(elements.len() + 1);
+                return Ok(val);
+            }
+        }
+        let start = self.pos;
+        let token = self.token();
+        match token.as_str() {
+            "true" => return Ok(7),
+            "false" => return Ok(3),
+            "nil" => return Ok(1),
+            "" => return Err(format!("unexpected '{}' at position {}", self.chars[start], start + 1)),
+            _ => {}
+        }
+        let digits = token.strip_prefix('-').unwrap_or(&token);
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            let mut n = BigInt::from_i64(0);
+            for d in digits.chars() {
+                n = n.mul(&BigInt::from_i64(10)).add(&BigInt::from_i64(d as i64 - '0' as i64));
+            }
+            n.neg = token.starts_with('-');
+            let n = n.trim();
+            self.alloc(n.mag.len() + 2)?;
+            let res = n.to_val(self.heap);
+            self.heap = res.heap;
+            return Ok(res.val);
+        }
+        match token.parse::<f64>() {
+            Ok(f) if token.contains('.') => {
+                self.alloc(2)?;
+                let res = alloc_float(f, self.heap);
+                self.heap = res.heap;
+                Ok(res.val)
+            }
+            _ => Err(format!("'{token}' at position {} is not a snek value", start + 1)),
         }
-        res.pop();
-        res.push(')');
-        seen.pop();
-        return res;
     }
 }
 
-fn parse_input(input: &str) -> i64 {
-    // TODO: parse the input string into internal value representation
-    if input == "false" {
-        3
-    } else if input == "true" {
-        7
-    } else {
-        input.parse::<i64>().expect("illegal argument") << 1
+fn parse_value(text: &str, heap: *mut u64) -> Result<SnekAlloc, String> {
+    let mut parser = InputParser { chars: text.chars().collect(), pos: 0, heap };
+    let val = parser.value()?;
+    parser.skip_space();
+    if parser.pos < parser.chars.len() {
+        return Err(format!("unexpected '{}' after the value", parser.chars[parser.pos]));
+    }
+    Ok(SnekAlloc { val, heap: parser.heap })
+}
+
+// parse a command line value, exiting with a message when it is not a snek value
+fn parse_input(input: &str, heap: *mut u64) -> SnekAlloc {
+    match parse_value(input, heap) {
+        Ok(res) => res,
+        Err(message) => {
+            eprintln!("invalid input {input:?}: {message}");
+            std::process::exit(1);
+        }
     }
 }
 
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let input = if args.len() == 2 { &args[1] } else { "false" };
-    let input = parse_input(&input);
     // Allocate a large memory space
     let total_size: usize = 1024 * 8 ; // 1024 * 8 byte
     let mut data= Vec::with_capacity(total_size);
     let starting_addr : *mut u8 = data.as_mut_ptr();
-    let output: i64 = unsafe { our_code_starts_here(input, starting_addr) };
+    unsafe {
+        SNEK_HEAP_END = starting_addr.add(total_size) as *const u64;
+    }
+    // the arguments live at the start of the heap, the snek code allocates after them.
+    // input is the first one
+    let mut heap = starting_addr as *mut u64;
+    let mut values = Vec::new();
+    for arg in &args[1..] {
+        let res = parse_input(arg, heap);
+        values.push(res.val);
+        heap = res.heap;
+    }
+    let input = values.first().copied().unwrap_or(3);
+    heap_check(heap, values.len() + 1);
+    unsafe {
+        *heap = (values.len() as u64) << 1;
+        for (i, val) in values.iter().enumerate() {
+            *heap.add(i + 1) = *val as u64;
+        }
+        SNEK_ARGS = heap as i64 + 1;
+        heap = heap.add(values.len() + 1);
+    }
+    let output: i64 = unsafe { our_code_starts_here(input, heap as *mut u8) };
     let mut seen = Vec::<i64>::new();
-    snek_print(output);
+    snek_print(output, 1);
 }
@@ -1,6 +1,7 @@
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 
 use sexp::Atom::*;
 use sexp::*;
@@ -13,6 +14,7 @@ enum Val {
     Reg(Reg),
     Imm(i64),
     RegOffset(Reg, i64),
+    Global(String),
     Bool(bool),
     Nil
 }
@@ -21,14 +23,97 @@ enum Val {
 enum Reg {
     RAX,
     RSP,
+    RBP,
     RDI,
     RBX,
+    RSI,
+    RDX,
+    RCX,
     R15,
 }
 
-const KEY_WORDS: [&'static str; 20] = [
+// header byte of heap objects that are not tuples, a tuple header is always even (length << 1)
+const BIGNUM_TAG: i64 = 3;
+const FLOAT_TAG: i64 = 5;
+// structs also keep their type id in bits 8 to 31 of the header and their field count above
+const STRUCT_TAG: i64 = 11;
+// a function value points at a static object: header, code address, arity and function id
+const FUNCTION_TAG: i64 = 13;
+
+// the type a failing operation expected, shared with snek_error in the runtime
+const EXPECT_NUMBER: i64 = 1;
+const EXPECT_TUPLE: i64 = 2;
+const EXPECT_FUNCTION: i64 = 9;
+
+// operation codes shared with snek_arith in the runtime
+const ARITH_PLUS: i64 = 0;
+const ARITH_MINUS: i64 = 1;
+const ARITH_TIMES: i64 = 2;
+
+// operation codes shared with snek_compare in the runtime
+const CMP_LESS: i64 = 0;
+const CMP_LESS_EQUAL: i64 = 1;
+const CMP_GREATER: i64 = 2;
+const CMP_GREATER_EQUAL: i64 = 3;
+
+#[derive(Debug)]
+struct Context {
+    // report overflow (error 101) instead of promoting the result to a bignum
+    overflow_error: bool,
+    // every place that can raise a runtime error, indexed by site id
+    sites: Vec<Site>,
+    // position of the innermost expression being compiled
+    pos: Option<Pos>,
+    // struct types by id and the functions generated for them
+    structs: Vec<StructDef>,
+    struct_ops: HashMap<String, StructOp>,
+    // function ids by global name, the functions with a rest parameter and the
+    // (id, label, arity) of every function used as a value
+    function_ids: HashMap<String, i64>,
+    variadic: HashSet<String>,
+    function_values: Vec<(i64, String, i64)>,
+}
+
+// (struct name (field ...)), or one variant of (data type (name field ...) ...)
+#[derive(Debug, Clone)]
+struct StructDef {
+    name: String,
+    fields: Vec<String>,
+    data: Option<String>,
+}
+
+// what a function generated for a struct does, with the struct id
+#[derive(Debug, Clone)]
+enum StructOp {
+    New(i64),
+    Get(i64, i64),
+    Set(i64, i64),
+    Is(i64),
+}
+
+#[derive(Debug, Clone)]
+struct Pos {
+    file: String,
+    line: usize,
+    col: usize,
+}
+
+type Positions = HashMap<*const Sexp, Pos>;
+
+// an operation that can fail at runtime
+#[derive(Debug)]
+struct Site {
+    pos: Option<Pos>,
+    op: String,
+}
+
+const KEY_WORDS: [&str; 44] = [
     "let", "add1", "sub1", "block", "true", "false", "if", "break", "set!", "+", "-", "*", "<",
-    ">", "<=", ">=", "=", "isnum", "isbool", "input", 
+    ">", "<=", ">=", "=", "isnum", "isbool", "input", "isfloat", "to-float", "to-int",
+    "make-vector", "vec-len", "hash-new", "hash-get", "hash-set!",
+    "hash-has?", "hash-keys", "open-file", "read-file", "write-file", "close-file", "cond",
+    "else", "and", "or", "when", "unless", "let*", "match",
+    "struct", "data",
 ];
 
 #[derive(Debug)]
@@ -44,15 +129,19 @@ enum Instr {
     CMove(Val, Val),
     Cmp(Val, Val),
     Sar(Val, Val),
+    Shl(Val, Val),
+    Lea(Val, Val),
     Jg(String),
     Jl(String),
     Jge(String),
     Jle(String),
     Jo(String),
     Label(String),
-    Xor(Val, Val),
     And(Val, Val),
+    Or(Val, Val),
     Call(String),
+    Push(Val),
+    Pop(Val),
     Ret,
 }
 
@@ -62,6 +151,10 @@ enum Op1 {
     Sub1,
     IsNum,
     IsBool,
+    IsFloat,
+    ToFloat,
+    ToInt,
+    VecLen,
 }
 
 #[derive(Debug)]
@@ -76,9 +169,24 @@ enum Op2 {
     LessEqual,
 }
 
+// builtins on hash maps and files, all implemented by the runtime
+#[derive(Debug)]
+enum Builtin {
+    HashNew,
+    HashGet,
+    HashSet,
+    HashHas,
+    HashKeys,
+    OpenFile,
+    ReadFile,
+    WriteFile,
+    CloseFile,
+}
+
 #[derive(Debug)]
 enum Expr {
     Number(i64),
+    Float(f64),
     Boolean(bool),
     Id(String),
     Let(Vec<(String, Expr)>, Box<Expr>),
@@ -93,11 +201,36 @@ enum Expr {
     Tuple(Vec<Expr>),
     Index(Box<Expr>, Box<Expr>),
     SetTuple(Box<Expr>, Box<Expr>, Box<Expr>),
-    Nil
+    MakeVector(Box<Expr>, Box<Expr>),
+    Builtin(Builtin, Vec<Expr>),
+    Match(Box<Expr>, Vec<MatchArm>),
+    Nil,
+    Pos(Pos, Box<Expr>),
+}
+// a pattern of match, tuples match exactly their number of elements and
+// (name p ...) a struct or variant of that name with one pattern per field
+#[derive(Debug)]
+enum Pattern {
+    Wildcard,
+    Var(String),
+    Number(i64),
+    Boolean(bool),
+    Nil,
+    Tuple(Vec<Pattern>),
+    Variant(String, Vec<Pattern>),
+}
+
+#[derive(Debug)]
+struct MatchArm {
+    pattern: Pattern,
+    guard: Option<Expr>,
+    body: Expr,
 }
+
 #[derive(Debug)]
+// name, parameters, the rest parameter of (fun (f a . rest) ...) and the body
 enum Def {
-    Func(String, Vec<String>, Box<Expr>),
+    Func(String, Vec<String>, Option<String>, Box<Expr>),
 }
 #[derive(Debug)]
 enum Lang {
@@ -106,29 +239,124 @@ enum Lang {
 }
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let mut ctx = Context {
+        overflow_error: false,
+        sites: Vec::new(),
+        pos: None,
+        structs: Vec::new(),
+        struct_ops: HashMap::new(),
+        function_ids: HashMap::new(),
+        variadic: HashSet::new(),
+        function_values: Vec::new(),
+    };
+    let mut trace_macros = false;
+    let mut lint = false;
+    args.retain(|arg| match arg.as_str() {
+        "--overflow-error" => {
+            ctx.overflow_error = true;
+            false
+        }
+        "--trace-macros" => {
+            trace_macros = true;
+            false
+        }
+        "--lint" => {
+            lint = true;
+            false
+        }
+        _ => true,
+    });
 
+    // diamondback check [--lint] file.snek compiles without writing anything
+    let check = args.len() > 1 && args[1] == "check";
+    if check {
+        args.remove(1);
+    }
     let in_name = &args[1];
-    let out_name = &args[2];
-    let mut in_file = File::open(in_name)?;
-    let mut in_contents = String::new();
-    in_file.read_to_string(&mut in_contents)?;
-    let contents = split_content(&in_contents.trim());
-    // let content = parse(contents[1]).expect("Invalid parentheses");
-    // let content = parse(&in_contents).expect("Invalid parentheses");
-
-    // sexp parse
-    let parsed_content = parse_all(contents);
-
-    // self parse
-    let mut parse_result = parse_func_expr(parsed_content);
-    let parse_expr = parse_result.pop().unwrap();
-    let parse_func = parse_result;
+    let out_name = if check { "" } else { &args[2] };
+
+    // the program and every module it imports, functions of imported modules are
+    // renamed to module.name so labels don't collide
+    let mut loader = Loader {
+        loaded: HashMap::new(),
+        loading: Vec::new(),
+        prefixes: HashSet::new(),
+        defs: Vec::new(),
+        structs: Vec::new(),
+        fun_positions: HashMap::new(),
+        disabled_lints: HashMap::new(),
+        prelude: HashMap::new(),
+        trace_macros,
+    };
+    load_prelude(&mut loader)?;
+    let (_, mut parse_result) = load_module(Path::new(in_name), true, &mut loader)?;
+    let mut parse_expr = parse_result.pop().unwrap();
+    let mut parse_func = loader.defs;
+    parse_func.append(&mut parse_result);
+    // only the prelude functions the program uses are compiled, and only the functions
+    // main reaches are emitted
+    let mut roots: Vec<&Expr> = Vec::new();
+    for piece in parse_func.iter().chain([&parse_expr]) {
+        match piece {
+            Lang::Def(Def::Func(fun, _, _, body)) if !fun.starts_with("prelude.") => roots.push(body),
+            Lang::Def(_) => {}
+            Lang::Expr(e) => roots.push(e),
+        }
+    }
+    let used = called_functions(&parse_func, &roots);
+    parse_func.retain(|piece| match piece {
+        Lang::Def(Def::Func(fun, _, _, _)) => !fun.starts_with("prelude.") || used.contains(fun),
+        Lang::Expr(_) => true,
+    });
+    ctx.struct_ops = struct_ops(&loader.structs);
+    ctx.structs = loader.structs;
+    // calls to functions with a rest parameter pass their extra arguments as one tuple
+    let mut variadic: HashMap<String, usize> = HashMap::unit("print".to_string(), 1);
+    for piece in &parse_func {
+        if let Lang::Def(Def::Func(fun, params, Some(_), _)) = piece {
+            variadic.insert(fun.clone(), params.len());
+            ctx.variadic.insert(fun.clone());
+        }
+    }
+    for piece in parse_func.iter_mut().chain([&mut parse_expr]) {
+        match piece {
+            Lang::Def(Def::Func(_, _, _, body)) => pack_rest_args(body, &variadic),
+            Lang::Expr(e) => pack_rest_args(e, &variadic),
+        }
+    }
+    let reachable = match &parse_expr {
+        Lang::Expr(e) => called_functions(&parse_func, &[e]),
+        Lang::Def(_) => HashSet::new(),
+    };
+    if check {
+        let lints = if lint {
+            lint_program(&parse_func, &parse_expr, &loader.fun_positions, &ctx.structs, &loader.disabled_lints)
+        } else {
+            Vec::new()
+        };
+        let mut label = 0;
+        let (_, func_map) = compile_func(parse_func, &reachable, &mut label, &mut ctx);
+        compile_expression(parse_expr, func_map, &mut label, &mut ctx);
+        for lint in &lints {
+            println!("{}", lint_str(lint));
+        }
+        return Ok(());
+    }
+    // in the order compile_func gives out the ids
+    let mut function_names = vec!["main".to_string()];
+    for piece in &parse_func {
+        if let Lang::Def(Def::Func(fun, _, _, _)) = piece {
+            if reachable.contains(fun) {
+                function_names.push(fun.clone());
+            }
+        }
+    }
     println!(" {:?}", parse_func);
     println!("{:?}", parse_expr);
     let mut label = 0;
-    let (defination, func_map) = compile_func(parse_func, &mut label);
-    let expr_result = compile_expression(parse_expr, func_map, &mut label);
+    let (defination, func_map) = compile_func(parse_func, &reachable, &mut label, &mut ctx);
+    let expr_result = compile_expression(parse_expr, func_map, &mut label, &mut ctx);
     // let result  = compile(parse_result);
     // let expr = parse_expr(&content);
     // let mut labels = 0;
@@ -139,46 +367,94 @@ fn main() -> std::io::Result<()> {
         "
         section .text
         global our_code_starts_here
+        global snek_function_count
+        global snek_function_names
+        global snek_site_count
+        global snek_sites
+        global snek_struct_count
+        global snek_structs
         extern snek_error
+        extern snek_frame
+        extern snek_site
+        extern snek_heap_end
+        extern snek_args
         extern snek_print
         extern snek_equal
+        extern snek_display
+        extern snek_newline
+        extern snek_read_line
+        extern snek_read_value
+        extern snek_arith
+        extern snek_compare
+        extern snek_eq
+        extern snek_to_float
+        extern snek_to_int
+        extern snek_make_vector
+        extern snek_hash_new
+        extern snek_hash_get
+        extern snek_hash_set
+        extern snek_hash_has
+        extern snek_hash_keys
+        extern snek_open_file
+        extern snek_read_file
+        extern snek_write_file
+        extern snek_close_file
         error_handling_starts_here:
         index_out_of_bound:
           mov rdi, 102
+          mov rcx, rdx
+          jmp throw_error
+        no_match:
+          mov rdi, 111
           jmp throw_error
         not_tuple:
           mov rdi, 100
+          mov rcx, {EXPECT_TUPLE}
           jmp throw_error
         invalid_argument:
           mov rdi, 99
+          mov rcx, {EXPECT_NUMBER}
+          jmp throw_error
+        not_function:
+          mov rdi, 99
+          mov rcx, {EXPECT_FUNCTION}
+          jmp throw_error
+        wrong_arity:
+          mov rdi, 113
+          mov rcx, rdx
+          jmp throw_error
+        not_struct:
+          mov rdi, 112
+          mov rcx, rdx
           jmp throw_error
         overflow:
           mov rdi, 101
           jmp throw_error
+        out_of_memory:
+          mov rdi, 104
+          jmp throw_error
         throw_error:
-          push rsp
+          mov [rel snek_frame], rbp
+          mov rdx, rax
+          mov rsp, rbp
+          sub rsp, 16
           call snek_error
           ret
         function_defination_starts_here:
-        print:
-          mov rdi, [rsp + 8]
-          push rsp
-          call snek_print
-          pop rsp
-          ret
-        equal:
-          mov rdi, [rsp + 8]
-          mov rsi, [rsp + 16]
-          call snek_equal
-          ret
+        {}
         {}
         our_code_starts_here:
           mov r15,rsi
           {}
           ret
-
+        section .data
+        {}
+        {}
+        {}
+        {}
 ",
-        defination, expr_result
+        runtime_stubs(&reachable), defination, expr_result, function_table(&function_names), site_table(&ctx.sites),
+        struct_table(&ctx.structs), function_value_table(&ctx.function_values)
     );
 
     let mut out_file = File::create(out_name)?;
@@ -187,108 +463,235 @@ fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-fn parse_expr(s: &Sexp) -> Expr {
+// lists remember where they were in the source, so runtime errors can point at them
+fn parse_expr(s: &Sexp, pos: &Positions) -> Expr {
+    let expr = parse_form(s, pos);
+    match pos.get(&(s as *const Sexp)) {
+        Some(p) => Expr::Pos(p.clone(), Box::new(expr)),
+        None => expr,
+    }
+}
+
+fn parse_form(s: &Sexp, pos: &Positions) -> Expr {
     match s {
         Sexp::Atom(I(n)) => Expr::Number(i64::try_from(*n).unwrap()),
+        Sexp::Atom(F(f)) => Expr::Float(*f),
         Sexp::Atom(S(name)) if name == "true" => Expr::Boolean(true),
         Sexp::Atom(S(name)) if name == "false" => Expr::Boolean(false),
         Sexp::Atom(S(name)) if name == "nil" => Expr::Nil,
         Sexp::Atom(S(id)) => Expr::Id(id.to_string()),
         Sexp::List(vec) => match &vec[..] {
             [Sexp::Atom(S(op)), e] if op == "add1" => {
-                Expr::UnOp(Op1::Add1, Box::new(parse_expr(e)))
+                Expr::UnOp(Op1::Add1, Box::new(parse_expr(e, pos)))
             }
             [Sexp::Atom(S(op)), e] if op == "sub1" => {
-                Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e)))
+                Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e, pos)))
             }
             [Sexp::Atom(S(op)), e] if op == "isnum" => {
-                Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e)))
+                Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e, pos)))
             }
             [Sexp::Atom(S(op)), e] if op == "isbool" => {
-                Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e)))
+                Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e, pos)))
+            }
+            [Sexp::Atom(S(op)), e] if op == "isfloat" => {
+                Expr::UnOp(Op1::IsFloat, Box::new(parse_expr(e, pos)))
+            }
+            [Sexp::Atom(S(op)), e] if op == "to-float" => {
+                Expr::UnOp(Op1::ToFloat, Box::new(parse_expr(e, pos)))
+            }
+            [Sexp::Atom(S(op)), e] if op == "to-int" => {
+                Expr::UnOp(Op1::ToInt, Box::new(parse_expr(e, pos)))
+            }
+            [Sexp::Atom(S(op)), e] if op == "vec-len" => {
+                Expr::UnOp(Op1::VecLen, Box::new(parse_expr(e, pos)))
             }
             [Sexp::Atom(S(op)), e1, e2] if op == "+" => Expr::BinOp(
                 Op2::Plus,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == "-" => Expr::BinOp(
                 Op2::Minus,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == "*" => Expr::BinOp(
                 Op2::Times,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == "=" => Expr::BinOp(
                 Op2::Equal,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == ">" => Expr::BinOp(
                 Op2::Greater,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == "<" => Expr::BinOp(
                 Op2::Less,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == ">=" => Expr::BinOp(
                 Op2::GreaterEqual,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2] if op == "<=" => Expr::BinOp(
                 Op2::LessEqual,
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
             [Sexp::Atom(S(op)), e1, e2, e3] if op == "if" => Expr::If(
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-                Box::new(parse_expr(e3)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
+                Box::new(parse_expr(e3, pos)),
             ),
             [Sexp::Atom(S(op)), exprs @ ..] if op == "block" => {
-                Expr::Block(exprs.into_iter().map(parse_expr).collect())
+                Expr::Block(exprs.iter().map(|e| parse_expr(e, pos)).collect())
             }
             [Sexp::Atom(S(op)), exprs @ ..] if op == "tuple" => {
-                Expr::Tuple(exprs.into_iter().map(parse_expr).collect())
+                Expr::Tuple(exprs.iter().map(|e| parse_expr(e, pos)).collect())
             }
             [Sexp::Atom(S(op)), e1, e2] if op == "index" => Expr::Index(
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "make-vector" => Expr::MakeVector(
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
             ),
+            [Sexp::Atom(S(op))] if op == "hash-new" => Expr::Builtin(Builtin::HashNew, vec![]),
+            [Sexp::Atom(S(op)), e1, e2] if op == "hash-get" => {
+                Expr::Builtin(Builtin::HashGet, vec![parse_expr(e1, pos), parse_expr(e2, pos)])
+            }
+            [Sexp::Atom(S(op)), e1, e2, e3] if op == "hash-set!" => Expr::Builtin(
+                Builtin::HashSet,
+                vec![parse_expr(e1, pos), parse_expr(e2, pos), parse_expr(e3, pos)],
+            ),
+            [Sexp::Atom(S(op)), e1, e2] if op == "hash-has?" => {
+                Expr::Builtin(Builtin::HashHas, vec![parse_expr(e1, pos), parse_expr(e2, pos)])
+            }
+            [Sexp::Atom(S(op)), e] if op == "hash-keys" => {
+                Expr::Builtin(Builtin::HashKeys, vec![parse_expr(e, pos)])
+            }
+            [Sexp::Atom(S(op)), e1, e2] if op == "open-file" => {
+                Expr::Builtin(Builtin::OpenFile, vec![parse_expr(e1, pos), parse_expr(e2, pos)])
+            }
+            [Sexp::Atom(S(op)), e] if op == "read-file" => {
+                Expr::Builtin(Builtin::ReadFile, vec![parse_expr(e, pos)])
+            }
+            [Sexp::Atom(S(op)), e1, e2] if op == "write-file" => {
+                Expr::Builtin(Builtin::WriteFile, vec![parse_expr(e1, pos), parse_expr(e2, pos)])
+            }
+            [Sexp::Atom(S(op)), e] if op == "close-file" => {
+                Expr::Builtin(Builtin::CloseFile, vec![parse_expr(e, pos)])
+            }
             [Sexp::Atom(S(op)), e1, e2, e3] if op == "settuple" => Expr::SetTuple(
-                Box::new(parse_expr(e1)),
-                Box::new(parse_expr(e2)),
-                Box::new(parse_expr(e3)),
+                Box::new(parse_expr(e1, pos)),
+                Box::new(parse_expr(e2, pos)),
+                Box::new(parse_expr(e3, pos)),
             ),
             [Sexp::Atom(S(op)), name, e] if op == "set!" => {
-                Expr::Set(name.to_string(), Box::new(parse_expr(e)))
+                Expr::Set(name.to_string(), Box::new(parse_expr(e, pos)))
             }
-            [Sexp::Atom(S(op)), e] if op == "loop" => Expr::Loop(Box::new(parse_expr(e))),
-            [Sexp::Atom(S(op)), e] if op == "break" => Expr::Break(Box::new(parse_expr(e))),
+            [Sexp::Atom(S(op)), e] if op == "loop" => Expr::Loop(Box::new(parse_expr(e, pos))),
+            [Sexp::Atom(S(op)), e] if op == "break" => Expr::Break(Box::new(parse_expr(e, pos))),
             [Sexp::Atom(S(op)), Sexp::List(bind_expr), e] if op == "let" => {
                 let mut vars: Vec<(String, Expr)> = Vec::new();
                 for bind in bind_expr {
-                    vars.push(parse_bind(bind))
+                    vars.push(parse_bind(bind, pos))
                 }
                 if vars.len() == 0 {
                     panic!("Invalid no binding")
                 }
-                Expr::Let(vars, Box::new(parse_expr(e)))
+                Expr::Let(vars, Box::new(parse_expr(e, pos)))
+            }
+            [Sexp::Atom(S(op)), clauses @ ..] if op == "cond" => parse_cond(clauses, pos),
+            [Sexp::Atom(S(op)), exprs @ ..] if op == "and" => parse_and(exprs, pos),
+            [Sexp::Atom(S(op)), exprs @ ..] if op == "or" => parse_or(exprs, pos),
+            [Sexp::Atom(S(op)), test, body @ ..] if op == "when" || op == "unless" => {
+                if body.is_empty() {
+                    panic!("Invalid {}, expected a body after the test", op)
+                }
+                let body = Expr::Block(body.iter().map(|e| parse_expr(e, pos)).collect());
+                let (then, other) = if op == "when" {
+                    (body, Expr::Boolean(false))
+                } else {
+                    (Expr::Boolean(false), body)
+                };
+                Expr::If(Box::new(parse_expr(test, pos)), Box::new(then), Box::new(other))
+            }
+            [Sexp::Atom(S(op)), Sexp::List(bind_expr), e] if op == "let*" => {
+                // one let per binding, so each one sees the ones before it
+                let body = parse_expr(e, pos);
+                bind_expr.iter().rev().fold(body, |body, bind| {
+                    Expr::Let(vec![parse_bind(bind, pos)], Box::new(body))
+                })
+            }
+            [Sexp::Atom(S(op)), e, arms @ ..] if op == "match" => {
+                let arms: Vec<MatchArm> = arms.iter().map(|arm| parse_arm(arm, pos)).collect();
+                if arms.is_empty() {
+                    panic!("Invalid match, expected at least one arm")
+                }
+                warn_unreachable_arms(&arms, arm_positions(&vec[2..], pos));
+                Expr::Match(Box::new(parse_expr(e, pos)), arms)
+            }
+            [Sexp::Atom(S(op)), ..]
+                if ["cond", "when", "unless", "let*", "else", "match"].contains(&op.as_str()) =>
+            {
+                panic!("Invalid {} form", op)
             }
             [Sexp::Atom(S(func_name)), exprs @ ..] => Expr::Call(
                 func_name.to_string(),
-                exprs.into_iter().map(parse_expr).collect(),
+                exprs.iter().map(|e| parse_expr(e, pos)).collect(),
             ),
             _ => panic!("Invalid parse error"),
         },
-        _ => panic!("Invalid parse error"),
+    }
+}
+
+fn op1_name(op: &Op1) -> &'static str {
+    match op {
+        Op1::Add1 => "add1",
+        Op1::Sub1 => "sub1",
+        Op1::IsNum => "isnum",
+        Op1::IsBool => "isbool",
+        Op1::IsFloat => "isfloat",
+        Op1::ToFloat => "to-float",
+        Op1::ToInt => "to-int",
+        Op1::VecLen => "vec-len",
+    }
+}
+
+// name of the builtin and the runtime function implementing it
+fn builtin_names(op: &Builtin) -> (&'static str, &'static str) {
+    match op {
+        Builtin::HashNew => ("hash-new", "snek_hash_new"),
+        Builtin::HashGet => ("hash-get", "snek_hash_get"),
+        Builtin::HashSet => ("hash-set!", "snek_hash_set"),
+        Builtin::HashHas => ("hash-has?", "snek_hash_has"),
+        Builtin::HashKeys => ("hash-keys", "snek_hash_keys"),
+        Builtin::OpenFile => ("open-file", "snek_open_file"),
+        Builtin::ReadFile => ("read-file", "snek_read_file"),
+        Builtin::WriteFile => ("write-file", "snek_write_file"),
+        Builtin::CloseFile => ("close-file", "snek_close_file"),
+    }
+}
+
+fn op2_name(op: &Op2) -> &'static str {
+    match op {
+        Op2::Plus => "+",
+        Op2::Minus => "-",
+        Op2::Times => "*",
+        Op2::Equal => "=",
+        Op2::Greater => ">",
+        Op2::Less => "<",
+        Op2::GreaterEqual => ">=",
+        Op2::LessEqual => "<=",
     }
 }
 
@@ -298,14 +701,161 @@ fn new_label(l: &mut i32, s: &str) -> String {
     format!("{s}_{current}")
 }
 
-fn parse_bind(s: &Sexp) -> (String, Expr) {
+// (cond (test e) ... (else e)) is a chain of ifs, false when no test holds and there is no else
+fn parse_cond(clauses: &[Sexp], pos: &Positions) -> Expr {
+    let mut res = Expr::Boolean(false);
+    for (i, clause) in clauses.iter().enumerate().rev() {
+        res = match clause {
+            Sexp::List(vec) => match &vec[..] {
+                [Sexp::Atom(S(op)), body @ ..] if op == "else" && !body.is_empty() => {
+                    if i != clauses.len() - 1 {
+                        panic!("Invalid cond, else must be the last clause")
+                    }
+                    Expr::Block(body.iter().map(|e| parse_expr(e, pos)).collect())
+                }
+                [test, body @ ..] if !body.is_empty() => Expr::If(
+                    Box::new(parse_expr(test, pos)),
+                    Box::new(Expr::Block(body.iter().map(|e| parse_expr(e, pos)).collect())),
+                    Box::new(res),
+                ),
+                _ => panic!("Invalid cond clause {}, expected (test body ...)", clause),
+            },
+            _ => panic!("Invalid cond clause {}, expected (test body ...)", clause),
+        };
+    }
+    res
+}
+
+// (and a b ...) is false as soon as one of them is, otherwise the last value
+fn parse_and(exprs: &[Sexp], pos: &Positions) -> Expr {
+    match exprs {
+        [] => Expr::Boolean(true),
+        [e] => parse_expr(e, pos),
+        [e, rest @ ..] => Expr::If(
+            Box::new(parse_expr(e, pos)),
+            Box::new(parse_and(rest, pos)),
+            Box::new(Expr::Boolean(false)),
+        ),
+    }
+}
+
+// (or a b ...) is the first value that isn't false, the value is kept in a
+// variable user code can't name
+fn parse_or(exprs: &[Sexp], pos: &Positions) -> Expr {
+    match exprs {
+        [] => Expr::Boolean(false),
+        [e] => parse_expr(e, pos),
+        [e, rest @ ..] => {
+            let tmp = "or%value".to_string();
+            Expr::Let(
+                vec![(tmp.clone(), parse_expr(e, pos))],
+                Box::new(Expr::If(
+                    Box::new(Expr::Id(tmp.clone())),
+                    Box::new(Expr::Id(tmp)),
+                    Box::new(parse_or(rest, pos)),
+                )),
+            )
+        }
+    }
+}
+
+// [pattern body] or [pattern when guard body]
+fn parse_arm(arm: &Sexp, pos: &Positions) -> MatchArm {
+    match arm {
+        Sexp::List(vec) => match &vec[..] {
+            [pattern, body] => MatchArm {
+                pattern: parse_pattern(pattern, &mut Vec::new()),
+                guard: None,
+                body: parse_expr(body, pos),
+            },
+            [pattern, Sexp::Atom(S(op)), guard, body] if op == "when" => MatchArm {
+                pattern: parse_pattern(pattern, &mut Vec::new()),
+                guard: Some(parse_expr(guard, pos)),
+                body: parse_expr(body, pos),
+            },
+            _ => panic!("Invalid match arm {}, expected [pattern body] or [pattern when guard body]", arm),
+        },
+        _ => panic!("Invalid match arm {}, expected [pattern body] or [pattern when guard body]", arm),
+    }
+}
+
+// names lists the variables bound so far, a pattern can't bind one twice
+fn parse_pattern(s: &Sexp, names: &mut Vec<String>) -> Pattern {
+    match s {
+        Sexp::Atom(I(n)) => Pattern::Number(*n),
+        Sexp::Atom(S(name)) => match name.as_str() {
+            "_" => Pattern::Wildcard,
+            "true" => Pattern::Boolean(true),
+            "false" => Pattern::Boolean(false),
+            "nil" => Pattern::Nil,
+            _ => {
+                if KEY_WORDS.contains(&name.as_str()) {
+                    panic!("Invalid pattern, {} is a keyword", name)
+                }
+                if names.contains(name) {
+                    panic!("Invalid pattern, {} is bound twice", name)
+                }
+                names.push(name.clone());
+                Pattern::Var(name.clone())
+            }
+        },
+        Sexp::List(vec) => match &vec[..] {
+            [Sexp::Atom(S(op)), elements @ ..] if op == "tuple" && !elements.is_empty() => {
+                Pattern::Tuple(elements.iter().map(|e| parse_pattern(e, names)).collect())
+            }
+            [Sexp::Atom(S(name)), fields @ ..] if name != "tuple" && !KEY_WORDS.contains(&name.as_str()) => {
+                Pattern::Variant(name.clone(), fields.iter().map(|e| parse_pattern(e, names)).collect())
+            }
+            _ => panic!("Invalid pattern {}", s),
+        },
+        _ => panic!("Invalid pattern {}", s),
+    }
+}
+
+fn arm_positions(arms: &[Sexp], pos: &Positions) -> Vec<Option<Pos>> {
+    arms.iter().map(|arm| pos.get(&(arm as *const Sexp)).cloned()).collect()
+}
+
+// whether every value matching q also matches p
+fn pattern_covers(p: &Pattern, q: &Pattern) -> bool {
+    match (p, q) {
+        (Pattern::Wildcard | Pattern::Var(_), _) => true,
+        (Pattern::Number(a), Pattern::Number(b)) => a == b,
+        (Pattern::Boolean(a), Pattern::Boolean(b)) => a == b,
+        (Pattern::Nil, Pattern::Nil) => true,
+        (Pattern::Tuple(ps), Pattern::Tuple(qs)) => {
+            ps.len() == qs.len() && ps.iter().zip(qs).all(|(p, q)| pattern_covers(p, q))
+        }
+        (Pattern::Variant(a, ps), Pattern::Variant(b, qs)) => {
+            a == b && ps.len() == qs.len() && ps.iter().zip(qs).all(|(p, q)| pattern_covers(p, q))
+        }
+        _ => false,
+    }
+}
+
+fn warn_unreachable_arms(arms: &[MatchArm], positions: Vec<Option<Pos>>) {
+    for (i, arm) in arms.iter().enumerate() {
+        let covered = arms[..i]
+            .iter()
+            .any(|earlier| earlier.guard.is_none() && pattern_covers(&earlier.pattern, &arm.pattern));
+        if covered {
+            let at = match &positions[i] {
+                Some(pos) => format!("{}:{}:{}: ", pos.file, pos.line, pos.col),
+                None => String::new(),
+            };
+            eprintln!("{}warning: unreachable match arm, an earlier arm matches everything it does", at);
+        }
+    }
+}
+
+fn parse_bind(s: &Sexp, pos: &Positions) -> (String, Expr) {
     match s {
         Sexp::List(vec) => match &vec[..] {
             [Sexp::Atom(S(n)), e] => {
                 if KEY_WORDS.contains(&&n[..]) {
                     panic!("illegal parameter name, {} is a keyword", n)
                 }
-                (n.to_string(), parse_expr(e))
+                (n.to_string(), parse_expr(e, pos))
             }
             _ => panic!("Invalid"),
         },
@@ -320,6 +870,7 @@ fn compile_to_instrs(
     brake: &String,
     l: &mut i32,
     func_map: HashMap<String, i64>,
+    ctx: &mut Context,
 ) -> Vec<Instr> {
     let mut instrs: Vec<Instr> = Vec::new();
     match e {
@@ -330,6 +881,18 @@ fn compile_to_instrs(
             }
             instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(*n << 1)));
         }
+        Expr::Float(f) => {
+            // floats are boxed: a header followed by the bits of the f64
+            let site = new_site(ctx, "float");
+            instrs.append(&mut heap_check(2, site));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm((1 << 32) | FLOAT_TAG)));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::R15, 0), Val::Reg(Reg::RBX)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(f.to_bits() as i64)));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::R15, -8), Val::Reg(Reg::RBX)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::R15)));
+            instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1)));
+            instrs.push(Instr::IAdd(Val::Reg(Reg::R15), Val::Imm(16)));
+        }
         Expr::Boolean(true) => {
             instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(true)));
         }
@@ -347,6 +910,14 @@ fn compile_to_instrs(
                     }
                     instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDI)));
                 }
+                // the command line arguments are a global tuple set up by the runtime, a
+                // variable named args hides it. %args can't be shadowed, it is used by arg-count
+                "%args" => {
+                    instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Global("snek_args".to_string())));
+                }
+                "args" if !env.contains_key(s) => {
+                    instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Global("snek_args".to_string())));
+                }
                 "let" | "if" | "block" | "loop" | "break" => {
                     panic!("illegal name, {} is a keyword", s)
                 }
@@ -359,6 +930,15 @@ fn compile_to_instrs(
                             Val::Reg(Reg::RAX),
                             Val::RegOffset(Reg::RSP, offset),
                         ));
+                    } else if let Some(&id) = ctx.function_ids.get(s) {
+                        if ctx.variadic.contains(s) {
+                            panic!("Invalid, {} has a rest parameter and can't be used as a value", s)
+                        }
+                        if !ctx.function_values.iter().any(|(other, _, _)| *other == id) {
+                            ctx.function_values.push((id, func_label(s), *func_map.get(s).unwrap()));
+                        }
+                        instrs.push(Instr::Lea(Val::Reg(Reg::RAX), Val::Global(format!("snek_function_value_{}", id))));
+                        instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1)));
                     } else {
                         panic!("Unbound variable identifier {}", s);
                     }
@@ -366,32 +946,53 @@ fn compile_to_instrs(
             }
         }
         Expr::UnOp(op, expr) => match op {
-            Op1::Add1 => {
-                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
-                instrs.append(&mut new_instrs);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
-                instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(2)));
-                instrs.push(Instr::Jo("overflow".to_string()));
-            }
-            Op1::Sub1 => {
-                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
+            Op1::Add1 | Op1::Sub1 => {
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
                 instrs.append(&mut new_instrs);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
-                instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(2)));
-                instrs.push(Instr::Jo("overflow".to_string()));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
+                let slow_label = new_label(l, "arithslow");
+                let end_label = new_label(l, "arithend");
+                instrs.push(Instr::Test(Val::Reg(Reg::RAX), Val::Imm(1)));
+                instrs.push(Instr::Jne(slow_label.clone()));
+                let op_code = match op {
+                    Op1::Add1 => {
+                        instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(2)));
+                        ARITH_PLUS
+                    }
+                    _ => {
+                        instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Imm(2)));
+                        ARITH_MINUS
+                    }
+                };
+                instrs.append(&mut arith_slow_path(
+                    op_code,
+                    op1_name(op),
+                    [Val::RegOffset(Reg::RSP, si * 8), Val::Imm(2)],
+                    si + 1,
+                    slow_label,
+                    end_label,
+                    ctx,
+                ));
             }
             Op1::IsNum => {
-                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
                 instrs.append(&mut new_instrs);
+                let true_label = new_label(l, "isnumtrue");
+                let false_label = new_label(l, "isnumfalse");
+                let end_label = new_label(l, "isnumend");
                 instrs.push(Instr::Test(Val::Reg(Reg::RAX), Val::Imm(1)));
+                instrs.push(Instr::Je(true_label.clone()));
+                // a bignum is a number as well
+                instrs.append(&mut test_heap_tag(BIGNUM_TAG, &false_label));
+                instrs.push(Instr::Label(true_label));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(true)));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(false_label));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(false)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Bool(true)));
-                instrs.push(Instr::CMove(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+                instrs.push(Instr::Label(end_label));
             }
             Op1::IsBool => {
-                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
                 instrs.append(&mut new_instrs);
                 instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Imm(3)));
                 instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(3)));
@@ -399,50 +1000,107 @@ fn compile_to_instrs(
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Bool(true)));
                 instrs.push(Instr::CMove(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
             }
+            Op1::IsFloat => {
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
+                instrs.append(&mut new_instrs);
+                let false_label = new_label(l, "isfloatfalse");
+                let end_label = new_label(l, "isfloatend");
+                instrs.append(&mut test_heap_tag(FLOAT_TAG, &false_label));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(true)));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(false_label));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(false)));
+                instrs.push(Instr::Label(end_label));
+            }
+            Op1::ToFloat | Op1::ToInt => {
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
+                instrs.append(&mut new_instrs);
+                let func = match op {
+                    Op1::ToFloat => "snek_to_float",
+                    _ => "snek_to_int",
+                };
+                let site = new_site(ctx, op1_name(op));
+                instrs.append(&mut call_runtime(
+                    func,
+                    vec![Val::Reg(Reg::RAX), Val::Reg(Reg::R15)],
+                    si,
+                    site,
+                ));
+                instrs.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RDX)));
+            }
+            Op1::VecLen => {
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
+                instrs.append(&mut new_instrs);
+                let site = new_site(ctx, "vec-len");
+                instrs.append(&mut tuple_check(si, site));
+                // the header is the length shifted left by one, which is already a snek number
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, (si + 1) * 8)));
+            }
         },
         Expr::BinOp(op, expr1, expr2) => match op {
             Op2::Plus | Op2::Minus | Op2::Times => {
-                let mut new_instrs2 = compile_to_instrs(expr2, si, env, brake, l, func_map.clone());
+                let mut new_instrs2 = compile_to_instrs(expr2, si, env, brake, l, func_map.clone(), ctx);
                 let stack_offset = si * 8;
                 instrs.append(&mut new_instrs2);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
                 instrs.push(Instr::IMov(
                     Val::RegOffset(Reg::RSP, stack_offset),
                     Val::Reg(Reg::RAX),
                 ));
                 let mut new_instrs1 =
-                    compile_to_instrs(expr1, si + 1, env, brake, l, func_map.clone());
+                    compile_to_instrs(expr1, si + 1, env, brake, l, func_map.clone(), ctx);
                 instrs.append(&mut new_instrs1);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
-                match op {
+                instrs.push(Instr::IMov(
+                    Val::RegOffset(Reg::RSP, stack_offset + 8),
+                    Val::Reg(Reg::RAX),
+                ));
+                let slow_label = new_label(l, "arithslow");
+                let end_label = new_label(l, "arithend");
+                // anything but two plain numbers goes through the runtime
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+                instrs.push(Instr::Or(
+                    Val::Reg(Reg::RBX),
+                    Val::RegOffset(Reg::RSP, stack_offset),
+                ));
+                instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(1)));
+                instrs.push(Instr::Jne(slow_label.clone()));
+                let op_code = match op {
                     Op2::Plus => {
                         instrs.push(Instr::IAdd(
                             Val::Reg(Reg::RAX),
                             Val::RegOffset(Reg::RSP, stack_offset),
                         ));
+                        ARITH_PLUS
                     }
                     Op2::Minus => {
                         instrs.push(Instr::ISub(
                             Val::Reg(Reg::RAX),
                             Val::RegOffset(Reg::RSP, stack_offset),
                         ));
+                        ARITH_MINUS
                     }
-                    Op2::Times => {
+                    _ => {
                         instrs.push(Instr::Sar(Val::Reg(Reg::RAX), Val::Imm(1)));
                         instrs.push(Instr::IMul(
                             Val::Reg(Reg::RAX),
                             Val::RegOffset(Reg::RSP, stack_offset),
                         ));
+                        ARITH_TIMES
                     }
-                    _ => {}
-                }
-                instrs.push(Instr::Jo("overflow".to_string()));
+                };
+                instrs.append(&mut arith_slow_path(
+                    op_code,
+                    op2_name(op),
+                    [Val::RegOffset(Reg::RSP, stack_offset + 8), Val::RegOffset(Reg::RSP, stack_offset)],
+                    si + 2,
+                    slow_label,
+                    end_label,
+                    ctx,
+                ));
             }
             Op2::Equal => {
-                let end_label = new_label(l, "ifend");
-                let mut new_instrs1 = compile_to_instrs(expr1, si, env, brake, l, func_map.clone());
+                let slow_label = new_label(l, "equalslow");
+                let end_label = new_label(l, "equalend");
+                let mut new_instrs1 = compile_to_instrs(expr1, si, env, brake, l, func_map.clone(), ctx);
                 let stack_offset = si * 8;
                 instrs.append(&mut new_instrs1);
                 instrs.push(Instr::IMov(
@@ -450,90 +1108,118 @@ fn compile_to_instrs(
                     Val::Reg(Reg::RAX),
                 ));
                 let mut new_instrs2 =
-                    compile_to_instrs(expr2, si + 1, env, brake, l, func_map.clone());
+                    compile_to_instrs(expr2, si + 1, env, brake, l, func_map.clone(), ctx);
                 instrs.append(&mut new_instrs2);
+                // two plain numbers are compared directly, everything else is checked by snek_eq
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
-                instrs.push(Instr::Xor(
+                instrs.push(Instr::Or(
                     Val::Reg(Reg::RBX),
                     Val::RegOffset(Reg::RSP, stack_offset),
                 ));
                 instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(1)));
-                instrs.push(Instr::Jne("invalid_argument".to_string()));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
-                instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(1)));
-                instrs.push(Instr::Je(end_label.clone()));
-                instrs.push(Instr::Xor(
-                    Val::Reg(Reg::RBX),
-                    Val::RegOffset(Reg::RSP, stack_offset),
-                ));
-                instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(3)));
-                instrs.push(Instr::Jne("invalid_argument".to_string()));
-                instrs.push(Instr::Label(end_label.clone()));
+                instrs.push(Instr::Jne(slow_label.clone()));
                 instrs.push(Instr::Cmp(
                     Val::Reg(Reg::RAX),
                     Val::RegOffset(Reg::RSP, stack_offset),
                 ));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(false)));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Bool(true)));
-                instrs.push(Instr::CMove(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)))
+                instrs.push(Instr::CMove(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(slow_label));
+                let site = new_site(ctx, op2_name(op));
+                instrs.append(&mut call_runtime(
+                    "snek_eq",
+                    vec![Val::RegOffset(Reg::RSP, stack_offset), Val::Reg(Reg::RAX)],
+                    si + 1,
+                    site,
+                ));
+                instrs.push(Instr::Label(end_label));
             }
             Op2::Greater | Op2::Less | Op2::GreaterEqual | Op2::LessEqual => {
                 let mut label = String::new();
                 let mut end_label = String::new();
                 let mut com_instrs = Vec::new();
                 let stack_offset = si * 8;
+                let slow_label = new_label(l, "compareslow");
+                let flags_label = new_label(l, "compareflags");
                 match op {
                     Op2::Greater => {
                         label = new_label(l, "greater");
                         end_label = new_label(l, "greaterend");
-                        com_instrs = compare_instrs(">", stack_offset, label, end_label);
+                        com_instrs = compare_instrs(">", label, end_label.clone());
                     }
                     Op2::Less => {
                         label = new_label(l, "less");
                         end_label = new_label(l, "lessend");
-                        com_instrs = compare_instrs("<", stack_offset, label, end_label);
+                        com_instrs = compare_instrs("<", label, end_label.clone());
                     }
                     Op2::GreaterEqual => {
                         label = new_label(l, "greaterequal");
                         end_label = new_label(l, "greaterequalend");
-                        com_instrs = compare_instrs(">=", stack_offset, label, end_label);
+                        com_instrs = compare_instrs(">=", label, end_label.clone());
                     }
                     Op2::LessEqual => {
                         label = new_label(l, "lessequal");
                         end_label = new_label(l, "lessequalend");
-                        com_instrs = compare_instrs("<=", stack_offset, label, end_label);
+                        com_instrs = compare_instrs("<=", label, end_label.clone());
                     }
                     _ => {}
                 }
-                let mut new_instrs2 = compile_to_instrs(expr2, si, env, brake, l, func_map.clone());
+                let mut new_instrs2 = compile_to_instrs(expr2, si, env, brake, l, func_map.clone(), ctx);
                 instrs.append(&mut new_instrs2);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
                 instrs.push(Instr::IMov(
                     Val::RegOffset(Reg::RSP, stack_offset),
                     Val::Reg(Reg::RAX),
                 ));
                 let mut new_instrs1 =
-                    compile_to_instrs(expr1, si + 1, env, brake, l, func_map.clone());
+                    compile_to_instrs(expr1, si + 1, env, brake, l, func_map.clone(), ctx);
                 instrs.append(&mut new_instrs1);
-                let mut test_instr = test_if_number();
-                instrs.append(&mut test_instr);
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+                instrs.push(Instr::Or(
+                    Val::Reg(Reg::RBX),
+                    Val::RegOffset(Reg::RSP, stack_offset),
+                ));
+                instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(1)));
+                instrs.push(Instr::Jne(slow_label.clone()));
+                instrs.push(Instr::Cmp(
+                    Val::Reg(Reg::RAX),
+                    Val::RegOffset(Reg::RSP, stack_offset),
+                ));
+                instrs.push(Instr::Jmp(flags_label.clone()));
+                // snek_compare returns the boolean itself, a NaN makes every comparison false
+                instrs.push(Instr::Label(slow_label));
+                let cmp_code = match op {
+                    Op2::Less => CMP_LESS,
+                    Op2::LessEqual => CMP_LESS_EQUAL,
+                    Op2::Greater => CMP_GREATER,
+                    _ => CMP_GREATER_EQUAL,
+                };
+                let site = new_site(ctx, op2_name(op));
+                instrs.append(&mut call_runtime(
+                    "snek_compare",
+                    vec![Val::Imm(cmp_code), Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, stack_offset)],
+                    si + 1,
+                    site,
+                ));
+                instrs.push(Instr::Jmp(end_label));
+                instrs.push(Instr::Label(flags_label));
                 instrs.append(&mut com_instrs);
             }
         },
         Expr::If(condition, thn, els) => {
             let label = new_label(l, "ifelse");
             let end_label = new_label(l, "ifend");
-            let mut cond_instrs = compile_to_instrs(condition, si, env, brake, l, func_map.clone());
+            let mut cond_instrs = compile_to_instrs(condition, si, env, brake, l, func_map.clone(), ctx);
             let stack_offset = si * 8;
             instrs.append(&mut cond_instrs);
             instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Bool(false)));
             instrs.push(Instr::Je(label.clone()));
-            let mut if_instrs = compile_to_instrs(thn, si, env, brake, l, func_map.clone());
+            let mut if_instrs = compile_to_instrs(thn, si, env, brake, l, func_map.clone(), ctx);
             instrs.append(&mut if_instrs);
             instrs.push(Instr::Jmp(end_label.clone()));
             instrs.push(Instr::Label(label.clone()));
-            let mut else_instrs = compile_to_instrs(els, si, env, brake, l, func_map.clone());
+            let mut else_instrs = compile_to_instrs(els, si, env, brake, l, func_map.clone(), ctx);
             instrs.append(&mut else_instrs);
             instrs.push(Instr::Label(end_label.clone()));
         }
@@ -549,6 +1235,7 @@ fn compile_to_instrs(
                     brake,
                     l,
                     func_map.clone(),
+                    ctx,
                 ));
             }
         }
@@ -556,7 +1243,7 @@ fn compile_to_instrs(
             let bool_key = env.contains_key(name);
             if bool_key == true {
                 let offset = env.get(name).unwrap() * 8;
-                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
+                let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
                 instrs.append(&mut new_instrs);
                 instrs.push(Instr::IMov(
                     Val::RegOffset(Reg::RSP, offset),
@@ -569,7 +1256,7 @@ fn compile_to_instrs(
         Expr::Loop(expr) => {
             let startloop = new_label(l, "loop");
             let endloop = new_label(l, "loopend");
-            let mut loop_instrs = compile_to_instrs(expr, si, env, &endloop, l, func_map);
+            let mut loop_instrs = compile_to_instrs(expr, si, env, &endloop, l, func_map, ctx);
             instrs.push(Instr::Label(startloop.clone()));
             instrs.append(&mut loop_instrs);
             instrs.push(Instr::Jmp(startloop));
@@ -579,7 +1266,7 @@ fn compile_to_instrs(
             if brake.len() == 0 {
                 panic!("unpaired break");
             }
-            let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map);
+            let mut new_instrs = compile_to_instrs(expr, si, env, brake, l, func_map, ctx);
             instrs.append(&mut new_instrs);
             instrs.push(Instr::Jmp(brake.to_string()));
         }
@@ -594,7 +1281,7 @@ fn compile_to_instrs(
                 } else {
                     set.insert(var.0.to_string());
                     let mut val_is =
-                        compile_to_instrs(&var.1, si + index, &dist, brake, l, func_map.clone());
+                        compile_to_instrs(&var.1, si + index, &dist, brake, l, func_map.clone(), ctx);
                     dist = dist.update(var.0.to_string(), si + index);
                     instrs.append(&mut val_is);
                     let stack_offset = (si + index) * 8;
@@ -605,9 +1292,53 @@ fn compile_to_instrs(
                 }
                 index += 1;
             }
-            let mut body_is = compile_to_instrs(expr, si + index, &dist, brake, l, func_map);
+            let mut body_is = compile_to_instrs(expr, si + index, &dist, brake, l, func_map, ctx);
             instrs.append(&mut body_is);
         }
+        // a variable holding a function value, called through the code address in it
+        Expr::Call(name, args) if env.contains_key(name) => {
+            let site = new_site(ctx, name);
+            let stack_offset = si * 8;
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, stack_offset), Val::Reg(Reg::RDI)));
+            let arity = args.len() as i64;
+            // the frame pointer pushed on function entry leaves rsp 16 byte aligned
+            let align_offset = if (si + arity) % 2 == 1 { 8 } else { 0 };
+            let mut param_offset = 8;
+            for arg in args {
+                instrs.append(&mut compile_to_instrs(arg, si + (param_offset + align_offset) / 8, env, brake, l, func_map.clone(), ctx));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, stack_offset + param_offset + align_offset), Val::Reg(Reg::RAX)));
+                param_offset += 8;
+            }
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, env.get(name).unwrap() * 8)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+            instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Imm(3)));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(1)));
+            instrs.append(&mut error_jump(Instr::Jne, "not_function", site));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Nil));
+            instrs.append(&mut error_jump(Instr::Je, "not_function", site));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RAX, 1)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Imm((3 << 32) | FUNCTION_TAG)));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Reg(Reg::RCX)));
+            instrs.append(&mut error_jump(Instr::Jne, "not_function", site));
+            // wrong_arity gets the number of arguments in rdx
+            instrs.push(Instr::IMov(Val::Reg(Reg::RDX), Val::Imm(arity)));
+            instrs.push(Instr::Cmp(Val::RegOffset(Reg::RAX, 1 - 16), Val::Reg(Reg::RDX)));
+            instrs.append(&mut error_jump(Instr::Jne, "wrong_arity", site));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 1 - 8)));
+            instrs.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(stack_offset + param_offset - 8 + align_offset)));
+            instrs.push(Instr::Call("rax".to_string()));
+            instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(stack_offset + param_offset - 8 + align_offset)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::RegOffset(Reg::RSP, stack_offset)));
+        }
+        Expr::Call(name, args) if ctx.struct_ops.contains_key(name) => {
+            let op = ctx.struct_ops.get(name).unwrap().clone();
+            instrs.append(&mut compile_struct_op(&op, name, args, si, env, brake, l, func_map, ctx));
+        }
+        // (arg-count) is a builtin unless the program defines a function of that name
+        Expr::Call(name, args) if name == "arg-count" && args.is_empty() && !func_map.contains_key(name) => {
+            let count = Expr::UnOp(Op1::VecLen, Box::new(Expr::Id("%args".to_string())));
+            instrs.append(&mut compile_to_instrs(&count, si, env, brake, l, func_map, ctx));
+        }
         Expr::Call(func_name, params) => {
           if !func_map.contains_key(func_name) {
             panic!("Invalid, function undefined")
@@ -620,20 +1351,21 @@ fn compile_to_instrs(
           let mut param_offset = 8;
           let param_len = params.len() as i64;
           let mut align_offset;
-          if (si + param_len) % 2 == 0{
+          // the frame pointer pushed on function entry leaves rsp 16 byte aligned
+          if (si + param_len) % 2 == 1 {
             align_offset = 8;
           } else {
             align_offset = 0;
           }
           for param in params {
             // why here, consider it later
-            instrs.append(&mut compile_to_instrs(param, si + (param_offset + align_offset) / 8 , env, brake, l, func_map.clone()));
+            instrs.append(&mut compile_to_instrs(param, si + (param_offset + align_offset) / 8 , env, brake, l, func_map.clone(), ctx));
             instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, stack_offset + param_offset + align_offset),
             Val::Reg(Reg::RAX)));
             param_offset += 8;
           }
           instrs.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(stack_offset + param_offset - 8 + align_offset)));
-          instrs.push(Instr::Call(func_name.to_string()));
+          instrs.push(Instr::Call(func_label(func_name)));
           instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(stack_offset + param_offset - 8 + align_offset)));
           instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::RegOffset(Reg::RSP, stack_offset )));
         }
@@ -660,6 +1392,7 @@ fn compile_to_instrs(
                     brake,
                     l,
                     func_map.clone(),
+                    ctx,
                 ));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX),
                 Val::RegOffset(Reg::RSP, si * 8)));
@@ -671,6 +1404,7 @@ fn compile_to_instrs(
             instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1)));
         }
         Expr::Index(pointer, index ) => {
+            let site = new_site(ctx, "index");
             instrs.append(&mut compile_to_instrs(
                 pointer,
                 si,
@@ -678,17 +1412,9 @@ fn compile_to_instrs(
                 brake,
                 l,
                 func_map.clone(),
+                ctx,
             ));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
-            instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Imm(3)));
-            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(1)));
-            instrs.push(Instr::Jne("not_tuple".to_string()));
-            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, si * 8)));
-            // unmarshal the pointer to a real address
-            instrs.push(Instr::ISub(Val::Reg(Reg::RBX), Val::Imm(1)));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RBX)));
-            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBX, 0)));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (si + 1) * 8), Val::Reg(Reg::RAX)));
+            instrs.append(&mut tuple_check(si, site));
             instrs.append(&mut compile_to_instrs(
                 index,
                 si + 2,
@@ -696,18 +1422,93 @@ fn compile_to_instrs(
                 brake,
                 l,
                 func_map.clone(),
+                ctx,
             ));
             instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (si + 2) * 8), Val::Reg(Reg::RAX)));
-            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, (si + 1) * 8)));
-            // index starts from 0
-            instrs.push(Instr::Jge("index_out_of_bound".to_string()));
+            instrs.append(&mut index_check(si, site));
             instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, (si + 2) * 8)));
             instrs.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Imm(4)));
             instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(8)));
             instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si * 8)));
             instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 0)));
         }
+        Expr::MakeVector(length, init) => {
+            instrs.append(&mut compile_to_instrs(
+                length,
+                si,
+                env,
+                brake,
+                l,
+                func_map.clone(),
+                ctx,
+            ));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
+            instrs.append(&mut compile_to_instrs(
+                init,
+                si + 1,
+                env,
+                brake,
+                l,
+                func_map.clone(),
+                ctx,
+            ));
+            // the length is only known at runtime, so the runtime checks it and fills the vector
+            let site = new_site(ctx, "make-vector");
+            instrs.append(&mut call_runtime(
+                "snek_make_vector",
+                vec![Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX), Val::Reg(Reg::R15)],
+                si + 1,
+                site,
+            ));
+            instrs.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RDX)));
+        }
+        Expr::Builtin(op, args) => {
+            let n = args.len() as i64;
+            let mut vals: Vec<Val> = Vec::new();
+            for (i, arg) in args.iter().enumerate() {
+                let slot = si + i as i64;
+                instrs.append(&mut compile_to_instrs(arg, slot, env, brake, l, func_map.clone(), ctx));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, slot * 8), Val::Reg(Reg::RAX)));
+                vals.push(Val::RegOffset(Reg::RSP, slot * 8));
+            }
+            // every builtin takes the heap pointer last and returns the new one in rdx
+            vals.push(Val::Reg(Reg::R15));
+            let (name, func) = builtin_names(op);
+            let site = new_site(ctx, name);
+            instrs.append(&mut call_runtime(func, vals, si + n, site));
+            instrs.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RDX)));
+        }
+        Expr::Match(scrutinee, arms) => {
+            check_missing_variants(arms, ctx);
+            let site = new_site(ctx, "match");
+            let end_label = new_label(l, "matchend");
+            instrs.append(&mut compile_to_instrs(scrutinee, si, env, brake, l, func_map.clone(), ctx));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
+            for arm in arms {
+                let next_label = new_label(l, "matchnext");
+                let mut arm_env = env.clone();
+                let mut next_si = si + 1;
+                instrs.append(&mut compile_pattern(&arm.pattern, si, &mut next_si, &mut arm_env, &next_label, ctx));
+                if let Some(guard) = &arm.guard {
+                    instrs.append(&mut compile_to_instrs(guard, next_si, &arm_env, brake, l, func_map.clone(), ctx));
+                    instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Bool(false)));
+                    instrs.push(Instr::Je(next_label.clone()));
+                }
+                instrs.append(&mut compile_to_instrs(&arm.body, next_si, &arm_env, brake, l, func_map.clone(), ctx));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(next_label));
+            }
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si * 8)));
+            instrs.append(&mut error_jump(Instr::Jmp, "no_match", site));
+            instrs.push(Instr::Label(end_label));
+        }
+        Expr::Pos(pos, expr) => {
+            let outer = ctx.pos.replace(pos.clone());
+            instrs.append(&mut compile_to_instrs(expr, si, env, brake, l, func_map, ctx));
+            ctx.pos = outer;
+        }
         Expr::SetTuple(pointer, index, val ) => {
+            let site = new_site(ctx, "settuple");
             instrs.append(&mut compile_to_instrs(
                 pointer,
                 si,
@@ -715,17 +1516,9 @@ fn compile_to_instrs(
                 brake,
                 l,
                 func_map.clone(),
+                ctx,
             ));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
-            instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Imm(3)));
-            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(1)));
-            instrs.push(Instr::Jne("not_tuple".to_string()));
-            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, si * 8)));
-            // unmarshal the pointer to a real address
-            instrs.push(Instr::ISub(Val::Reg(Reg::RBX), Val::Imm(1)));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RBX)));
-            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBX, 0)));
-            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (si + 1) * 8), Val::Reg(Reg::RAX)));
+            instrs.append(&mut tuple_check(si, site));
             instrs.append(&mut compile_to_instrs(
                 index,
                 si + 2,
@@ -733,11 +1526,10 @@ fn compile_to_instrs(
                 brake,
                 l,
                 func_map.clone(),
+                ctx,
             ));
             instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (si + 2) * 8), Val::Reg(Reg::RAX)));
-            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, (si + 1) * 8)));
-            // index starts from 0
-            instrs.push(Instr::Jge("index_out_of_bound".to_string()));
+            instrs.append(&mut index_check(si, site));
             instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, (si + 2) * 8)));
             instrs.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Imm(4)));
             instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(8)));
@@ -750,6 +1542,7 @@ fn compile_to_instrs(
                 brake,
                 l,
                 func_map.clone(),
+                ctx,
             ));
             instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, (si + 2) * 8)));
             instrs.push(Instr::IMov(Val::RegOffset(Reg::RBX, 0), Val::Reg(Reg::RAX)));
@@ -762,15 +1555,10 @@ fn compile_to_instrs(
 
 fn compare_instrs(
     operation: &str,
-    stack_offset: i64,
     label: String,
     end_label: String,
 ) -> Vec<Instr> {
     let mut instrs: Vec<Instr> = Vec::new();
-    instrs.push(Instr::Cmp(
-        Val::Reg(Reg::RAX),
-        Val::RegOffset(Reg::RSP, stack_offset),
-    ));
     match operation {
         ">" => {
             instrs.push(Instr::Jg(label.clone()));
@@ -858,6 +1646,12 @@ fn instr_to_str(i: &Instr) -> String {
             let str = format!("sar {}, {}\n", s_val1, s_val2);
             return str;
         }
+        Instr::Shl(val1, val2) => {
+            let s_val1 = val_to_str(val1);
+            let s_val2 = val_to_str(val2);
+            let str = format!("shl {}, {}\n", s_val1, s_val2);
+            return str;
+        }
         Instr::Jg(label) => {
             let str = format!("jg {}\n", label);
             return str;
@@ -882,26 +1676,29 @@ fn instr_to_str(i: &Instr) -> String {
             let str = format!("{}:\n", label);
             return str;
         }
-        Instr::Xor(val1, val2) => {
+        Instr::And(val1, val2) => {
             let s_val1 = val_to_str(val1);
             let s_val2 = val_to_str(val2);
-            let str = format!("xor {}, {}\n", s_val1, s_val2);
+            let str = format!("and {}, {}\n", s_val1, s_val2);
             return str;
         }
-        Instr::And(val1, val2) => {
+        Instr::Or(val1, val2) => {
             let s_val1 = val_to_str(val1);
             let s_val2 = val_to_str(val2);
-            let str = format!("and {}, {}\n", s_val1, s_val2);
+            let str = format!("or {}, {}\n", s_val1, s_val2);
             return str;
         }
         Instr::Ret => {
             let str = format!("ret\n");
             return str;
         }
+        Instr::Lea(val1, val2) => format!("lea {}, {}\n", val_to_str(val1), val_to_str(val2)),
         Instr::Call(label) => {
             let str = format!("call {}\n", label);
             return str;
         }
+        Instr::Push(val) => format!("push {}\n", val_to_str(val)),
+        Instr::Pop(val) => format!("pop {}\n", val_to_str(val)),
     }
 }
 
@@ -911,7 +1708,11 @@ fn val_to_str(v: &Val) -> String {
             Reg::RAX => return format!("rax"),
             Reg::RBX => return format!("rbx"),
             Reg::RSP => return format!("rsp"),
+            Reg::RBP => return format!("rbp"),
             Reg::RDI => return format!("rdi"),
+            Reg::RSI => return format!("rsi"),
+            Reg::RDX => return format!("rdx"),
+            Reg::RCX => return format!("rcx"),
             Reg::R15 => return format!("r15"),
         },
         Val::Imm(n) => return format!("{}", n),
@@ -925,9 +1726,21 @@ fn val_to_str(v: &Val) -> String {
             Reg::RSP => {
                 return format!("[rsp - {}]", offset);
             }
+            Reg::RBP => {
+                return format!("[rbp - {}]", offset);
+            }
             Reg::RDI => {
                 return format!("[rdi - {}]", offset);
             }
+            Reg::RSI => {
+                return format!("[rsi - {}]", offset);
+            }
+            Reg::RDX => {
+                return format!("[rdx - {}]", offset);
+            }
+            Reg::RCX => {
+                return format!("[rcx - {}]", offset);
+            }
             Reg::R15 => {
                 return format!("[r15 - {}]", offset);
             }
@@ -943,73 +1756,697 @@ fn val_to_str(v: &Val) -> String {
         Val::Nil => {
             return format!("{}", 1) 
         }
+        Val::Global(name) => format!("[rel {}]", name),
     }
 }
-fn test_if_number() -> Vec<Instr> {
-    let mut instrs: Vec<Instr> = Vec::new();
-    instrs.push(Instr::Test(Val::Reg(Reg::RAX), Val::Imm(1)));
-    instrs.push(Instr::Jne("invalid_argument".to_string()));
-    return instrs;
+// register a place that can raise a runtime error, returns its site id
+fn new_site(ctx: &mut Context, op: &str) -> i64 {
+    ctx.sites.push(Site {
+        pos: ctx.pos.clone(),
+        op: op.to_string(),
+    });
+    ctx.sites.len() as i64 - 1
 }
 
-// fn compile(e: &Expr, si: i64, env: &HashMap<String, i64>, brake: &String, l: &mut i32) -> String {
-//     let instrs = compile_to_instrs(e, si, env, brake, l);
-//     let mut strs = String::new();
-//     for i in &instrs {
+// the error labels expect the site id in rsi and the offending value in rax
+fn error_jump(jump: fn(String) -> Instr, label: &str, site: i64) -> Vec<Instr> {
+    vec![
+        Instr::IMov(Val::Reg(Reg::RSI), Val::Imm(site)),
+        jump(label.to_string()),
+    ]
+}
+
+// jump to out_of_memory unless words more words fit on the heap, clobbers rax and rbx
+fn heap_check(words: i64, site: i64) -> Vec<Instr> {
+    let mut instrs = vec![
+        Instr::IMov(Val::Reg(Reg::RBX), Val::Global("snek_heap_end".to_string())),
+        Instr::ISub(Val::Reg(Reg::RBX), Val::Reg(Reg::R15)),
+        Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(words << 1)),
+        Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(words * 8)),
+    ];
+    instrs.append(&mut error_jump(Instr::Jl, "out_of_memory", site));
+    instrs
+}
+
+fn struct_header(id: i64, fields: usize) -> i64 {
+    ((fields as i64) << 32) | (id << 8) | STRUCT_TAG
+}
+
+// jump to fail unless rax is a struct with type id, clobbers rbx and rcx
+fn struct_check(id: i64, fail: &str) -> Vec<Instr> {
+    vec![
+        Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)),
+        Instr::And(Val::Reg(Reg::RBX), Val::Imm(3)),
+        Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(1)),
+        Instr::Jne(fail.to_string()),
+        Instr::Cmp(Val::Reg(Reg::RAX), Val::Nil),
+        Instr::Je(fail.to_string()),
+        // compare the low 32 bits of the header, the field count is above them
+        Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RAX, 1)),
+        Instr::Shl(Val::Reg(Reg::RBX), Val::Imm(32)),
+        Instr::IMov(Val::Reg(Reg::RCX), Val::Imm(struct_header(id, 0) << 32)),
+        Instr::Cmp(Val::Reg(Reg::RBX), Val::Reg(Reg::RCX)),
+        Instr::Jne(fail.to_string()),
+    ]
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compile_struct_op(
+    op: &StructOp,
+    name: &str,
+    args: &[Expr],
+    si: i64,
+    env: &HashMap<String, i64>,
+    brake: &String,
+    l: &mut i32,
+    func_map: HashMap<String, i64>,
+    ctx: &mut Context,
+) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    let arity = match op {
+        StructOp::New(id) => ctx.structs[*id as usize].fields.len(),
+        StructOp::Get(..) | StructOp::Is(_) => 1,
+        StructOp::Set(..) => 2,
+    };
+    if args.len() != arity {
+        panic!("Invalid, wrong number of parameters for {}", name)
+    }
+    // not_struct expects the site in rsi and the struct id in rdx
+    let site = new_site(ctx, name);
+    let type_error = |id: i64| {
+        let mut instrs = vec![
+            Instr::IMov(Val::Reg(Reg::RSI), Val::Imm(site)),
+            Instr::IMov(Val::Reg(Reg::RDX), Val::Imm(id)),
+        ];
+        instrs.append(&mut struct_check(id, "not_struct"));
+        instrs
+    };
+    match op {
+        StructOp::New(id) => {
+            // same layout as a tuple, the fields follow the header
+            instrs.append(&mut heap_check(arity as i64 + 1, site));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(struct_header(*id, arity))));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::R15, 0), Val::Reg(Reg::RBX)));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::R15)));
+            instrs.push(Instr::IAdd(Val::Reg(Reg::R15), Val::Imm((arity as i64 + 1) * 8)));
+            for (i, arg) in args.iter().enumerate() {
+                instrs.append(&mut compile_to_instrs(arg, si + 1, env, brake, l, func_map.clone(), ctx));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, si * 8)));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RBX, -8 * (i as i64 + 1)), Val::Reg(Reg::RAX)));
+            }
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si * 8)));
+            instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Imm(1)));
+        }
+        StructOp::Get(id, i) => {
+            instrs.append(&mut compile_to_instrs(&args[0], si, env, brake, l, func_map, ctx));
+            instrs.append(&mut type_error(*id));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 1 - 8 * (i + 1))));
+        }
+        StructOp::Set(id, i) => {
+            instrs.append(&mut compile_to_instrs(&args[0], si, env, brake, l, func_map.clone(), ctx));
+            instrs.append(&mut type_error(*id));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RAX)));
+            instrs.append(&mut compile_to_instrs(&args[1], si + 1, env, brake, l, func_map, ctx));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, si * 8)));
+            instrs.push(Instr::IMov(Val::RegOffset(Reg::RBX, 1 - 8 * (i + 1)), Val::Reg(Reg::RAX)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+        }
+        StructOp::Is(id) => {
+            let false_label = new_label(l, "structfalse");
+            let end_label = new_label(l, "structend");
+            instrs.append(&mut compile_to_instrs(&args[0], si, env, brake, l, func_map, ctx));
+            instrs.append(&mut struct_check(*id, &false_label));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(true)));
+            instrs.push(Instr::Jmp(end_label.clone()));
+            instrs.push(Instr::Label(false_label));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Bool(false)));
+            instrs.push(Instr::Label(end_label));
+        }
+    }
+    instrs
+}
+
+// test the value in slot against pattern, jumping to fail when it doesn't match.
+// Variables are bound to slots from next_si on, which stay in use for the arm
+fn compile_pattern(
+    pattern: &Pattern,
+    slot: i64,
+    next_si: &mut i64,
+    env: &mut HashMap<String, i64>,
+    fail: &String,
+    ctx: &Context,
+) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    let literal = match pattern {
+        Pattern::Wildcard => return instrs,
+        Pattern::Var(name) => {
+            *env = env.update(name.clone(), slot);
+            return instrs;
+        }
+        Pattern::Number(n) => Val::Imm(n << 1),
+        Pattern::Boolean(b) => Val::Bool(*b),
+        Pattern::Nil => Val::Nil,
+        Pattern::Tuple(elements) => {
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, slot * 8)));
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+            instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Imm(3)));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(1)));
+            instrs.push(Instr::Jne(fail.clone()));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Nil));
+            instrs.push(Instr::Je(fail.clone()));
+            // a tuple header is its length shifted left by one, other heap objects have odd headers
+            instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RAX, 1)));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(elements.len() as i64 * 2)));
+            instrs.push(Instr::Jne(fail.clone()));
+            for (i, element) in elements.iter().enumerate() {
+                let element_slot = *next_si;
+                *next_si += 1;
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, slot * 8)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBX, 1 - 8 * (i as i64 + 1))));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, element_slot * 8), Val::Reg(Reg::RAX)));
+                instrs.append(&mut compile_pattern(element, element_slot, next_si, env, fail, ctx));
+            }
+            return instrs;
+        }
+        Pattern::Variant(name, fields) => {
+            let def = pattern_struct(name, fields.len(), ctx);
+            let id = ctx.structs.iter().position(|s| s.name == def.name).unwrap() as i64;
+            instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, slot * 8)));
+            instrs.append(&mut struct_check(id, fail));
+            for (i, field) in fields.iter().enumerate() {
+                let field_slot = *next_si;
+                *next_si += 1;
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, slot * 8)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RBX, 1 - 8 * (i as i64 + 1))));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, field_slot * 8), Val::Reg(Reg::RAX)));
+                instrs.append(&mut compile_pattern(field, field_slot, next_si, env, fail, ctx));
+            }
+            return instrs;
+        }
+    };
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), literal));
+    instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, slot * 8)));
+    instrs.push(Instr::Jne(fail.clone()));
+    instrs
+}
+
+// the struct or variant a pattern names, which needs a pattern for every field
+fn pattern_struct<'a>(name: &str, fields: usize, ctx: &'a Context) -> &'a StructDef {
+    let Some(def) = ctx.structs.iter().find(|s| s.name == name) else {
+        panic!("Invalid pattern, {} is not a struct or variant", name)
+    };
+    if def.fields.len() != fields {
+        panic!("Invalid pattern, {} has {} fields", name, def.fields.len())
+    }
+    def
+}
+
+// the head of a pattern for the exhaustiveness check, numbers and nil never
+// cover all values, so they only matter to tell a column is incomplete
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    Variant(String),
+    Boolean(bool),
+    Tuple(usize),
+    Other,
+}
+
+static WILDCARD: Pattern = Pattern::Wildcard;
+
+fn pattern_ctor(p: &Pattern) -> Option<(Ctor, Vec<&Pattern>)> {
+    match p {
+        Pattern::Wildcard | Pattern::Var(_) => None,
+        Pattern::Boolean(b) => Some((Ctor::Boolean(*b), Vec::new())),
+        Pattern::Tuple(ps) => Some((Ctor::Tuple(ps.len()), ps.iter().collect())),
+        Pattern::Variant(name, ps) => Some((Ctor::Variant(name.clone()), ps.iter().collect())),
+        Pattern::Number(_) | Pattern::Nil => Some((Ctor::Other, Vec::new())),
+    }
+}
+
+// the rows that can match a value built by ctor, with its fields in place of the first column
+fn specialize<'a>(rows: &[Vec<&'a Pattern>], ctor: &Ctor, arity: usize) -> Vec<Vec<&'a Pattern>> {
+    rows.iter()
+        .filter_map(|row| {
+            let mut fields = match pattern_ctor(row[0]) {
+                None => vec![&WILDCARD; arity],
+                Some((head, fields)) if head == *ctor => fields,
+                Some(_) => return None,
+            };
+            fields.extend_from_slice(&row[1..]);
+            Some(fields)
+        })
+        .collect()
+}
+
+fn ctor_str(ctor: &Ctor, fields: &[String]) -> String {
+    match ctor {
+        Ctor::Boolean(b) => b.to_string(),
+        Ctor::Tuple(_) => format!("(tuple {})", fields.join(" ")),
+        Ctor::Variant(name) if fields.is_empty() => format!("({})", name),
+        Ctor::Variant(name) => format!("({} {})", name, fields.join(" ")),
+        Ctor::Other => "_".to_string(),
+    }
+}
+
+// a row of patterns (one per column) that none of rows matches, None when they cover everything.
+// A column is complete when its heads are every variant of a data type, a struct,
+// both booleans or tuples of a single length, then each head is tried in turn
+fn missing_row(rows: &[Vec<&Pattern>], columns: usize, ctx: &Context) -> Option<Vec<String>> {
+    if columns == 0 {
+        return if rows.is_empty() { Some(Vec::new()) } else { None };
+    }
+    let mut heads: Vec<Ctor> = Vec::new();
+    for row in rows {
+        if let Some((head, fields)) = pattern_ctor(row[0]) {
+            if let Ctor::Variant(name) = &head {
+                pattern_struct(name, fields.len(), ctx);
+            }
+            if !heads.contains(&head) {
+                heads.push(head);
+            }
+        }
+    }
+    let arity = |ctor: &Ctor| match ctor {
+        Ctor::Variant(name) => ctx.structs.iter().find(|s| &s.name == name).unwrap().fields.len(),
+        Ctor::Tuple(n) => *n,
+        _ => 0,
+    };
+    let all: Option<Vec<Ctor>> = match heads.first() {
+        None => None,
+        Some(Ctor::Boolean(_)) => Some(vec![Ctor::Boolean(true), Ctor::Boolean(false)]),
+        Some(Ctor::Tuple(n)) => Some(vec![Ctor::Tuple(*n)]),
+        Some(Ctor::Variant(name)) => {
+            let def = ctx.structs.iter().find(|s| &s.name == name).unwrap();
+            Some(match &def.data {
+                Some(data) => ctx.structs.iter()
+                    .filter(|s| s.data.as_ref() == Some(data))
+                    .map(|s| Ctor::Variant(s.name.clone()))
+                    .collect(),
+                None => vec![Ctor::Variant(name.clone())],
+            })
+        }
+        Some(Ctor::Other) => None,
+    };
+    let all = all.filter(|all| heads.iter().all(|head| all.contains(head)));
+    match all {
+        Some(all) if all.iter().all(|ctor| heads.contains(ctor)) => {
+            for ctor in &all {
+                let n = arity(ctor);
+                if let Some(mut row) = missing_row(&specialize(rows, ctor, n), n + columns - 1, ctx) {
+                    let rest = row.split_off(n);
+                    let mut missing = vec![ctor_str(ctor, &row)];
+                    missing.extend(rest);
+                    return Some(missing);
+                }
+            }
+            None
+        }
+        all => {
+            // the values not built by any head only match rows starting with a wildcard
+            let defaults: Vec<Vec<&Pattern>> = rows.iter()
+                .filter(|row| pattern_ctor(row[0]).is_none())
+                .map(|row| row[1..].to_vec())
+                .collect();
+            let mut row = missing_row(&defaults, columns - 1, ctx)?;
+            let example = match all.and_then(|all| all.into_iter().find(|ctor| !heads.contains(ctor))) {
+                Some(ctor) => ctor_str(&ctor, &vec!["_".to_string(); arity(&ctor)]),
+                None => "_".to_string(),
+            };
+            row.insert(0, example);
+            Some(row)
+        }
+    }
+}
+
+// a match whose arms start with variants of a data type must handle every variant,
+// arms with a guard may not match so they don't count
+fn check_missing_variants(arms: &[MatchArm], ctx: &Context) {
+    let data = arms.iter().find_map(|arm| match &arm.pattern {
+        Pattern::Variant(name, fields) => pattern_struct(name, fields.len(), ctx).data.clone(),
+        _ => None,
+    });
+    let Some(data) = data else {
+        return;
+    };
+    let rows: Vec<Vec<&Pattern>> = arms.iter()
+        .filter(|arm| arm.guard.is_none())
+        .map(|arm| vec![&arm.pattern])
+        .collect();
+    if let Some(missing) = missing_row(&rows, 1, ctx) {
+        panic!("{}Invalid, match on {} is not exhaustive, {} is not matched", pos_prefix(&ctx.pos), data, missing[0]);
+    }
+}
+
+// check that rax is a tuple for index and settuple, leaves its address in
+// slot si and its header (the tagged length) in slot si + 1
+fn tuple_check(si: i64, site: i64) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+    instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Imm(3)));
+    instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(1)));
+    instrs.append(&mut error_jump(Instr::Jne, "not_tuple", site));
+    // unmarshal the pointer to a real address
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+    instrs.push(Instr::ISub(Val::Reg(Reg::RBX), Val::Imm(1)));
+    instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RBX)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RBX, 0)));
+    // other heap objects (bignums, floats) have an odd header
+    instrs.push(Instr::Test(Val::Reg(Reg::RBX), Val::Imm(1)));
+    instrs.append(&mut error_jump(Instr::Jne, "not_tuple", site));
+    instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (si + 1) * 8), Val::Reg(Reg::RBX)));
+    instrs
+}
+
+// check the index in rax is a number within the length tuple_check left in slot si + 1,
+// index_out_of_bound also gets the length in rdx
+fn index_check(si: i64, site: i64) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    instrs.push(Instr::Test(Val::Reg(Reg::RAX), Val::Imm(1)));
+    instrs.append(&mut error_jump(Instr::Jne, "invalid_argument", site));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RDX), Val::RegOffset(Reg::RSP, (si + 1) * 8)));
+    // index starts from 0
+    instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Imm(0)));
+    instrs.append(&mut error_jump(Instr::Jl, "index_out_of_bound", site));
+    instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+    instrs.append(&mut error_jump(Instr::Jge, "index_out_of_bound", site));
+    instrs
+}
+
+// jump to fail_label unless rax points to a heap object whose header byte is tag, clobbers rbx
+fn test_heap_tag(tag: i64, fail_label: &String) -> Vec<Instr> {
+    vec![
+        Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)),
+        Instr::And(Val::Reg(Reg::RBX), Val::Imm(3)),
+        Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(1)),
+        Instr::Jne(fail_label.to_string()),
+        Instr::Cmp(Val::Reg(Reg::RAX), Val::Nil),
+        Instr::Je(fail_label.to_string()),
+        Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RAX, 1)),
+        Instr::And(Val::Reg(Reg::RBX), Val::Imm(255)),
+        Instr::Cmp(Val::Reg(Reg::RBX), Val::Imm(tag)),
+        Instr::Jne(fail_label.to_string()),
+    ]
+}
+
+// asm label of a snek function, - is not allowed in labels
+fn func_label(name: &str) -> String {
+    name.replace('-', "$")
+}
+
+// every snek frame saves the caller's rbp and keeps its function id right below it,
+// which is how snek_error walks the stack for a backtrace
+fn frame_prologue(id: i64) -> Vec<Instr> {
+    vec![
+        Instr::Push(Val::Reg(Reg::RBP)),
+        Instr::IMov(Val::Reg(Reg::RBP), Val::Reg(Reg::RSP)),
+        Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(id)),
+        Instr::IMov(Val::RegOffset(Reg::RBP, 8), Val::Reg(Reg::RBX)),
+    ]
+}
+
+// names of the snek functions by id, read by the runtime when printing a backtrace
+fn function_table(names: &[String]) -> String {
+    let mut table = format!("align 8\nsnek_function_count:\n  dq {}\nsnek_function_names:\n", names.len());
+    for id in 0..names.len() {
+        table.push_str(&format!("  dq snek_function_name_{}\n", id));
+    }
+    for (id, name) in names.iter().enumerate() {
+        table.push_str(&format!("snek_function_name_{}:\n  db \"{}\", 0\n", id, name));
+    }
+    table
+}
+
+// the builtin functions that call into the runtime, only the ones the program uses are emitted
+fn runtime_stubs(reachable: &HashSet<String>) -> String {
+    let stubs = [
+        ("print", "
+        print:
+          mov rdi, [rsp + 16]
+          mov rsi, [rsp + 8]
+          push rsp
+          call snek_print
+          pop rsp
+          ret"),
+        ("equal", "
+        equal:
+          mov rdi, [rsp + 8]
+          mov rsi, [rsp + 16]
+          push rsp
+          call snek_equal
+          pop rsp
+          ret"),
+        ("display", "
+        display:
+          mov rdi, [rsp + 8]
+          push rsp
+          call snek_display
+          pop rsp
+          ret"),
+        ("newline", "
+        newline:
+          push rsp
+          call snek_newline
+          pop rsp
+          ret"),
+        ("read-line", "
+        read$line:
+          mov [rel snek_frame], rbp
+          mov rbx, -1
+          mov [rel snek_site], rbx
+          mov rdi, r15
+          push rsp
+          call snek_read_line
+          pop rsp
+          mov r15, rdx
+          ret"),
+        ("read-value", "
+        read$value:
+          mov [rel snek_frame], rbp
+          mov rbx, -1
+          mov [rel snek_site], rbx
+          mov rdi, r15
+          push rsp
+          call snek_read_value
+          pop rsp
+          mov r15, rdx
+          ret"),
+    ];
+    let mut code = String::new();
+    for (name, stub) in stubs {
+        if reachable.contains(name) {
+            code.push_str(stub);
+        }
+    }
+    code
+}
+
+// the static objects function values point at
+fn function_value_table(values: &[(i64, String, i64)]) -> String {
+    let mut table = String::from("align 8\n");
+    for (id, label, arity) in values {
+        table.push_str(&format!(
+            "snek_function_value_{}:\n  dq {}, {}, {}, {}\n",
+            id,
+            (3 << 32) | FUNCTION_TAG,
+            label,
+            arity,
+            id
+        ));
+    }
+    table
+}
+
+// name and field names of every struct type, indexed by the id in struct headers
+fn struct_table(structs: &[StructDef]) -> String {
+    let mut table = format!("align 8\nsnek_struct_count:\n  dq {}\nsnek_structs:\n", structs.len());
+    for (id, def) in structs.iter().enumerate() {
+        table.push_str(&format!("  dq snek_struct_name_{}, {}, snek_struct_fields_{}\n", id, def.fields.len(), id));
+    }
+    for (id, def) in structs.iter().enumerate() {
+        table.push_str(&format!("snek_struct_fields_{}:\n", id));
+        for i in 0..def.fields.len() {
+            table.push_str(&format!("  dq snek_struct_field_{}_{}\n", id, i));
+        }
+    }
+    // the strings go last so the pointers above stay aligned
+    for (id, def) in structs.iter().enumerate() {
+        table.push_str(&format!("snek_struct_name_{}:\n  db \"{}\", 0\n", id, def.name));
+        for (i, field) in def.fields.iter().enumerate() {
+            table.push_str(&format!("snek_struct_field_{}_{}:\n  db \"{}\", 0\n", id, i, field));
+        }
+    }
+    table
+}
+
+// source position and operation of every error site, read by snek_error
+fn site_table(sites: &[Site]) -> String {
+    let mut table = format!("align 8\nsnek_site_count:\n  dq {}\nsnek_sites:\n", sites.len());
+    for (id, site) in sites.iter().enumerate() {
+        let (line, col) = site.pos.as_ref().map_or((0, 0), |pos| (pos.line, pos.col));
+        table.push_str(&format!("  dq snek_site_file_{}, {}, {}, snek_site_op_{}\n", id, line, col, id));
+    }
+    for (id, site) in sites.iter().enumerate() {
+        let file = site.pos.as_ref().map_or("", |pos| &pos.file);
+        table.push_str(&format!("snek_site_file_{}:\n  db \"{}\", 0\n", id, file));
+        table.push_str(&format!("snek_site_op_{}:\n  db \"{}\", 0\n", id, site.op));
+    }
+    table
+}
+
+// call a function of the runtime from the middle of an expression, si is the first free stack slot.
+// site is reported if the runtime raises an error
+fn call_runtime(func: &str, args: Vec<Val>, si: i64, site: i64) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    // rdi holds input, keep it on the stack during the call
+    instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si * 8), Val::Reg(Reg::RDI)));
+    let arg_regs = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX];
+    for (arg, reg) in args.into_iter().zip(arg_regs) {
+        instrs.push(Instr::IMov(Val::Reg(reg), arg));
+    }
+    // rsp is 16 byte aligned inside snek code, keep the call aligned
+    let frame = if si % 2 == 1 { si * 8 + 8 } else { si * 8 };
+    // remember the current snek frame and site in case the runtime raises an error
+    instrs.push(Instr::IMov(Val::Global("snek_frame".to_string()), Val::Reg(Reg::RBP)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(site)));
+    instrs.push(Instr::IMov(Val::Global("snek_site".to_string()), Val::Reg(Reg::RBX)));
+    instrs.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Imm(frame)));
+    instrs.push(Instr::Call(func.to_string()));
+    instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Imm(frame)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::RegOffset(Reg::RSP, si * 8)));
+    instrs
+}
+
+// finish an arithmetic operation whose fast path has just run, the overflow flag
+// is still set from it. The slow path asks the runtime, which handles bignums
+fn arith_slow_path(
+    op_code: i64,
+    op_name: &str,
+    operands: [Val; 2],
+    si: i64,
+    slow_label: String,
+    end_label: String,
+    ctx: &mut Context,
+) -> Vec<Instr> {
+    let mut instrs: Vec<Instr> = Vec::new();
+    let site = new_site(ctx, op_name);
+    let [lhs, rhs] = operands;
+    if ctx.overflow_error {
+        instrs.append(&mut error_jump(Instr::Jo, "overflow", site));
+    } else {
+        instrs.push(Instr::Jo(slow_label.clone()));
+    }
+    instrs.push(Instr::Jmp(end_label.clone()));
+    instrs.push(Instr::Label(slow_label));
+    instrs.append(&mut call_runtime(
+        "snek_arith",
+        vec![Val::Imm(op_code), lhs, rhs, Val::Reg(Reg::R15)],
+        si,
+        site,
+    ));
+    // the runtime returns the new heap pointer next to the result
+    instrs.push(Instr::IMov(Val::Reg(Reg::R15), Val::Reg(Reg::RDX)));
+    instrs.push(Instr::Label(end_label));
+    instrs
+}
+
+// fn compile(e: &Expr, si: i64, env: &HashMap<String, i64>, brake: &String, l: &mut i32) -> String {
+//     let instrs = compile_to_instrs(e, si, env, brake, l);
+//     let mut strs = String::new();
+//     for i in &instrs {
 //         strs.push_str(&instr_to_str(i))
 //     }
 //     return (*strs.trim()).to_string();
 // }
-fn compile_func(parsed: Vec<Lang>, label: &mut i32) -> (String, HashMap<String, i64>) {
+// every function is compiled so errors in unused ones are still reported, but only
+// the ones in reachable end up in the output
+fn compile_func(
+    parsed: Vec<Lang>,
+    reachable: &HashSet<String>,
+    label: &mut i32,
+    ctx: &mut Context,
+) -> (String, HashMap<String, i64>) {
     let mut instrs: Vec<Instr> = Vec::new();
     let mut func_map: HashMap<String, i64> = HashMap::new();
-    func_map = func_map.update("print".to_string(), 1);
+    func_map = func_map.update("print".to_string(), 2);
     func_map = func_map.update("equal".to_string(), 2);
+    func_map = func_map.update("display".to_string(), 1);
+    func_map = func_map.update("newline".to_string(), 0);
+    func_map = func_map.update("read-line".to_string(), 0);
+    func_map = func_map.update("read-value".to_string(), 0);
+    if let Some(name) = ctx.struct_ops.keys().find(|name| func_map.contains_key(*name)) {
+        panic!("Invalid, struct function {} is already defined", name)
+    }
+    // function ids index the function table, 0 is the main expression. The table only
+    // lists the reachable functions, so they get the ids before the unreachable ones
     for piece in &parsed {
       match piece {
-        Lang::Def(Def::Func(fun, params, expr)) => {
-          let mut len = params.len() as i64;
+        Lang::Def(Def::Func(fun, params, rest, _)) => {
+          if reachable.contains(fun) {
+            ctx.function_ids.insert(fun.clone(), ctx.function_ids.len() as i64 + 1);
+          }
+          let len = (params.len() + rest.iter().len()) as i64;
           if func_map.contains_key(fun) {
             panic!("Invalid, multiple functions with same name")
           }
+          if ctx.struct_ops.contains_key(fun) {
+            panic!("Invalid, function {} is already defined by a struct", fun)
+          }
           func_map = func_map.update(fun.clone(), len);
         }
-        Lang::Expr(exp) => {
+        Lang::Expr(_) => {
           panic!("Invalid, format wrong, expression should be at the end of the program")
         }
       }
     } 
+    for piece in &parsed {
+        if let Lang::Def(Def::Func(fun, _, _, _)) = piece {
+            if !reachable.contains(fun) {
+                ctx.function_ids.insert(fun.clone(), ctx.function_ids.len() as i64 + 1);
+            }
+        }
+    }
     for piece in parsed {
         match piece {
-            Lang::Def(Def::Func(fun, params, expr)) => {
+            Lang::Def(Def::Func(fun, params, rest, expr)) => {
                 // check if there are multiple same name parameters in a function
-                // also set the env to contain all the parameter
-                let mut len = params.len() as i64;
+                // also set the env to contain all the parameter, the rest parameter is the last one
+                let mut len = (params.len() + rest.iter().len()) as i64;
                 let mut env: HashMap<String, i64> = HashMap::new();
-                for param in params {
+                for param in params.into_iter().chain(rest) {
                     if env.contains_key(&param) {
                         panic!("Invalid, Duplicate name for parameters");
                     } else {
-                        env = env.update(param, -len);
+                        // skip the return address and the saved frame pointer
+                        env = env.update(param, -len - 1);
                         len -= 1;
                     }
                 }
                 // set input to a particular value so if id's address is this value, panic and return
                 env = env.update("input".to_string(), i64::min_value());
                 // compile the function express with the env of params as variables
+                let (sites, values) = (ctx.sites.len(), ctx.function_values.len());
                 let mut new_instrs: Vec<Instr> = Vec::new();
-                new_instrs.push(Instr::Label(fun.clone()));
+                new_instrs.push(Instr::Label(func_label(&fun)));
+                new_instrs.append(&mut frame_prologue(ctx.function_ids[&fun]));
                 new_instrs.append(&mut compile_to_instrs(
                     &expr,
                     2,
                     &env,
                     &String::from(""),
                     label,
-                    func_map.clone()));
+                    func_map.clone(),
+                    ctx));
+                new_instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
                 new_instrs.push(Instr::Ret);
-                instrs.append(&mut new_instrs);
+                if reachable.contains(&fun) {
+                    instrs.append(&mut new_instrs);
+                } else {
+                    // nothing of an unreachable function ends up in the output
+                    ctx.sites.truncate(sites);
+                    ctx.function_values.truncate(values);
+                }
             }
-            Lang::Expr(exp) => {
+            Lang::Expr(_) => {
                 panic!("Invalid, format wrong, expression should be at the end of the program")
             }
         }
@@ -1021,13 +2458,15 @@ fn compile_func(parsed: Vec<Lang>, label: &mut i32) -> (String, HashMap<String,
     return ((*strs.trim()).to_string(), func_map);
 }
 
-fn compile_expression(expression: Lang, func_map: HashMap<String, i64>, label: &mut i32) -> String {
+fn compile_expression(expression: Lang, func_map: HashMap<String, i64>, label: &mut i32, ctx: &mut Context) -> String {
     let mut instrs: Vec<Instr> = Vec::new();
     let mut env: HashMap<String, i64> = HashMap::new();
     match expression {
         Lang::Expr(exp) => {
-            let mut new_instrs: Vec<Instr> = compile_to_instrs(&exp, 2, &env, &String::from(""), label, func_map);
+            instrs.append(&mut frame_prologue(0));
+            let mut new_instrs: Vec<Instr> = compile_to_instrs(&exp, 2, &env, &String::from(""), label, func_map, ctx);
                 instrs.append(&mut new_instrs);
+            instrs.push(Instr::Pop(Val::Reg(Reg::RBP)));
         }
         _ => {
             panic!("Invalid, the last piece should be a expression, not a defination")
@@ -1063,35 +2502,1121 @@ fn split_content(content: &str) -> Vec<&str> {
     }
     return res;
 }
-fn parse_all(contents: Vec<&str>) -> Vec<Sexp> {
-    let mut res: Vec<Sexp> = Vec::new();
-    for piece in contents {
-        res.push(parse(piece).expect("Invalid parentheses"));
+// modules already compiled into the program and the chain of imports being loaded
+struct Loader {
+    loaded: HashMap<PathBuf, HashMap<String, String>>,
+    loading: Vec<PathBuf>,
+    prefixes: HashSet<String>,
+    defs: Vec<Lang>,
+    structs: Vec<StructDef>,
+    // where each function was defined, by its global name
+    fun_positions: HashMap<String, Pos>,
+    // lint rules turned off by (disable-lint id ...), by file
+    disabled_lints: HashMap<String, Vec<String>>,
+    prelude: HashMap<String, String>,
+    trace_macros: bool,
+}
+
+// load a .snek file and the modules it imports. Its functions go to loader.defs,
+// except for the main program whose pieces are returned with the main expression last.
+// Also returns the functions the module provides, by local and by global name
+fn load_module(
+    path: &Path,
+    is_main: bool,
+    loader: &mut Loader,
+) -> std::io::Result<(HashMap<String, String>, Vec<Lang>)> {
+    let key = path.canonicalize()?;
+    if let Some(exports) = loader.loaded.get(&key) {
+        return Ok((exports.clone(), Vec::new()));
     }
-    return res;
+    if let Some(start) = loader.loading.iter().position(|p| *p == key) {
+        let mut chain: Vec<String> = loader.loading[start..].iter().map(|p| p.display().to_string()).collect();
+        chain.push(key.display().to_string());
+        panic!("Invalid, import cycle {}", chain.join(" -> "));
+    }
+    loader.loading.push(key.clone());
+    let mut in_contents = String::new();
+    File::open(path)?.read_to_string(&mut in_contents)?;
+    let (exports, pieces) = load_source(path, &in_contents, is_main, loader)?;
+    loader.loading.pop();
+    loader.loaded.insert(key, exports.clone());
+    Ok((exports, pieces))
+}
+
+// the prelude ships inside the compiler, its functions are visible in every module
+// that doesn't define or import the same names
+const PRELUDE: &str = include_str!("prelude.snek");
+
+fn load_prelude(loader: &mut Loader) -> std::io::Result<()> {
+    let (exports, _) = load_source(Path::new("prelude.snek"), PRELUDE, false, loader)?;
+    loader.prelude = exports;
+    Ok(())
 }
 
-fn parse_func_expr(contents: Vec<Sexp>) -> Vec<Lang> {
-    let mut res: Vec<Lang> = Vec::new();
-    for segment in contents {
-        let s = segment.clone();
-        match segment {
+fn load_source(
+    path: &Path,
+    in_contents: &str,
+    is_main: bool,
+    loader: &mut Loader,
+) -> std::io::Result<(HashMap<String, String>, Vec<Lang>)> {
+    let file_name = path.to_string_lossy().to_string();
+    // [ ] are the same as ( ), replaced byte for byte so positions stay the same
+    let in_contents = in_contents.replace('[', "(").replace(']', ")");
+    let contents = split_content(in_contents.trim());
+    let parsed_content = parse_all(contents.clone());
+    let positions = sexp_positions(&file_name, &in_contents, &contents, &parsed_content);
+
+    // imports and provides are handled here, the rest is parsed as usual
+    let mut visible: HashMap<String, String> = HashMap::new();
+    let mut provides: Option<Vec<String>> = None;
+    // positions are keyed by node address, so keep borrowing the original nodes
+    let mut forms: Vec<&Sexp> = Vec::new();
+    for form in &parsed_content {
+        match form {
             Sexp::List(vec) => match &vec[..] {
-                [Sexp::Atom(S(op)), e1, e2] if op == "fun" => {
-                    res.push(Lang::Def(parse_func(e1, e2)));
+                [Sexp::Atom(S(op)), Sexp::Atom(S(file))] if op == "import" => {
+                    let dir = path.parent().unwrap_or(Path::new(""));
+                    let (exports, _) = load_module(&dir.join(file), false, loader)?;
+                    for (name, global) in exports {
+                        match visible.get(&name) {
+                            Some(other) if *other != global => {
+                                panic!("Invalid, {} is provided by more than one import of {}", name, file_name)
+                            }
+                            _ => visible.insert(name, global),
+                        };
+                    }
                 }
-                _ => {
-                    res.push(Lang::Expr(parse_expr(&s)));
+                [Sexp::Atom(S(op)), ..] if op == "import" => panic!("Invalid import in {}", file_name),
+                [Sexp::Atom(S(op)), Sexp::Atom(S(name)), Sexp::List(fields)] if op == "struct" => {
+                    loader.structs.push(parse_struct(name, fields));
+                }
+                [Sexp::Atom(S(op)), ..] if op == "struct" => {
+                    panic!("Invalid struct in {}, expected (struct name (field ...))", file_name)
+                }
+                [Sexp::Atom(S(op)), Sexp::Atom(S(name)), variants @ ..] if op == "data" => {
+                    loader.structs.append(&mut parse_data(name, variants));
+                }
+                [Sexp::Atom(S(op)), ..] if op == "data" => {
+                    panic!("Invalid data in {}, expected (data name (variant field ...) ...)", file_name)
+                }
+                [Sexp::Atom(S(op)), rules @ ..] if op == "disable-lint" => {
+                    let disabled = loader.disabled_lints.entry(file_name.clone()).or_default();
+                    for rule in rules {
+                        match rule {
+                            Sexp::Atom(S(rule)) if LINT_RULES.iter().any(|(id, _)| id == rule) => {
+                                disabled.push(rule.to_string())
+                            }
+                            _ => panic!("Invalid disable-lint in {}, unknown rule {}", file_name, rule),
+                        }
+                    }
+                }
+                [Sexp::Atom(S(op)), names @ ..] if op == "provide" => {
+                    let provided = provides.get_or_insert_with(Vec::new);
+                    for name in names {
+                        match name {
+                            Sexp::Atom(S(name)) => provided.push(name.to_string()),
+                            _ => panic!("Invalid provide in {}", file_name),
+                        }
+                    }
                 }
+                _ => forms.push(form),
             },
-            _ => {
-                res.push(Lang::Expr(parse_expr(&s)));
+            _ => forms.push(form),
+        }
+    }
+    // macros are local to the file, they are expanded before anything is parsed
+    let mut expander = Expander { macros: HashMap::new(), trace: loader.trace_macros, fresh: 0 };
+    let mut syntax: Vec<Syntax> = Vec::new();
+    for form in forms {
+        match form {
+            Sexp::List(vec) if matches!(&vec[..], [Sexp::Atom(S(op)), ..] if op == "define-syntax") => {
+                let mac = parse_macro(&to_syntax(form, &positions));
+                expander.macros.insert(mac.name.clone(), mac);
+            }
+            _ => syntax.push(to_syntax(form, &positions)),
+        }
+    }
+    let syntax: Vec<Syntax> = syntax.into_iter().map(|form| expander.expand_top(form)).collect();
+    let expanded: Vec<Sexp> = syntax.iter().map(to_sexp).collect();
+    let mut positions: Positions = HashMap::new();
+    for (form, sexp) in syntax.iter().zip(&expanded) {
+        syntax_positions(form, sexp, &mut positions);
+    }
+    let mut pieces: Vec<Lang> = expanded.iter().map(|form| parse_piece(form, &positions)).collect();
+
+    let prefix = if is_main {
+        String::new()
+    } else {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let mut prefix = stem.clone();
+        let mut n = 2;
+        while loader.prefixes.contains(&prefix) {
+            prefix = format!("{}{}", stem, n);
+            n += 1;
+        }
+        loader.prefixes.insert(prefix.clone());
+        format!("{}.", prefix)
+    };
+    let mut locals: HashMap<String, String> = HashMap::new();
+    for piece in &pieces {
+        if let Lang::Def(Def::Func(fun, _, _, _)) = piece {
+            locals.insert(fun.clone(), format!("{}{}", prefix, fun));
+        }
+    }
+    // the module's own functions hide imported ones, which hide the prelude
+    let mut scope = loader.prelude.clone();
+    scope.extend(visible);
+    scope.extend(locals.clone());
+    for (form, piece) in expanded.iter().zip(pieces.iter_mut()) {
+        match piece {
+            Lang::Def(Def::Func(fun, params, rest, body)) => {
+                rename_calls(body, &scope, &params.iter().chain(rest.iter()).cloned().collect());
+                *fun = scope.get(fun).unwrap().clone();
+                if let Some(pos) = positions.get(&(form as *const Sexp)) {
+                    loader.fun_positions.insert(fun.clone(), pos.clone());
+                }
+            }
+            Lang::Expr(exp) => rename_calls(exp, &scope, &HashSet::new()),
+        }
+    }
+
+    let exports = match provides {
+        Some(names) => {
+            let mut exports = HashMap::new();
+            for name in names {
+                match locals.get(&name) {
+                    Some(global) => exports.insert(name, global.clone()),
+                    None => panic!("Invalid, {} provides {} which it does not define", file_name, name),
+                };
+            }
+            exports
+        }
+        None => locals,
+    };
+    if is_main {
+        if !matches!(pieces.last(), Some(Lang::Expr(_))) {
+            panic!("Invalid, the last piece should be a expression, not a defination")
+        }
+        return Ok((exports, pieces));
+    }
+    for piece in pieces {
+        match piece {
+            Lang::Def(_) => loader.defs.push(piece),
+            Lang::Expr(_) => panic!("Invalid, module {} can only define functions", file_name),
+        }
+    }
+    Ok((exports, Vec::new()))
+}
+
+// the expressions directly inside e
+fn sub_exprs(e: &Expr) -> Vec<&Expr> {
+    match e {
+        Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_) | Expr::Id(_) | Expr::Nil => Vec::new(),
+        Expr::Let(binds, body) => {
+            let mut es: Vec<&Expr> = binds.iter().map(|(_, e)| e).collect();
+            es.push(body);
+            es
+        }
+        Expr::UnOp(_, e) | Expr::Set(_, e) | Expr::Loop(e) | Expr::Break(e) | Expr::Pos(_, e) => vec![e],
+        Expr::BinOp(_, e1, e2) | Expr::Index(e1, e2) | Expr::MakeVector(e1, e2) => vec![e1, e2],
+        Expr::If(e1, e2, e3) | Expr::SetTuple(e1, e2, e3) => vec![e1, e2, e3],
+        Expr::Block(es) | Expr::Tuple(es) | Expr::Builtin(_, es) | Expr::Call(_, es) => es.iter().collect(),
+        Expr::Match(e, arms) => {
+            let mut es: Vec<&Expr> = vec![e];
+            for arm in arms {
+                es.extend(arm.guard.as_ref());
+                es.push(&arm.body);
+            }
+            es
+        }
+    }
+}
+
+// names of the functions e calls or uses as values, in order
+fn calls_in(e: &Expr, calls: &mut Vec<String>) {
+    if let Expr::Call(name, _) | Expr::Id(name) = e {
+        if !calls.contains(name) {
+            calls.push(name.clone());
+        }
+    }
+    for sub in sub_exprs(e) {
+        calls_in(sub, calls);
+    }
+}
+
+// the rules of check --lint, each one can be turned off for a file with (disable-lint id ...)
+const LINT_RULES: [(&str, Severity); 9] = [
+    ("unused-function", Severity::Warning),
+    ("unreachable-function", Severity::Warning),
+    ("recursion", Severity::Note),
+    ("unreachable-code", Severity::Warning),
+    ("dead-set", Severity::Warning),
+    ("pure-expression", Severity::Warning),
+    ("infinite-loop", Severity::Warning),
+    ("shadowed-parameter", Severity::Warning),
+    ("tuple-equality", Severity::Warning),
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Severity {
+    Warning,
+    Note,
+}
+
+#[derive(Debug)]
+struct Lint {
+    rule: &'static str,
+    pos: Option<Pos>,
+    message: String,
+}
+
+fn lint(rule: &'static str, pos: Option<&Pos>, message: String) -> Lint {
+    Lint { rule, pos: pos.cloned(), message }
+}
+
+fn lint_str(lint: &Lint) -> String {
+    let severity = match LINT_RULES.iter().find(|(id, _)| *id == lint.rule) {
+        Some((_, Severity::Note)) => "note",
+        _ => "warning",
+    };
+    format!("{}{}[{}]: {}", pos_prefix(&lint.pos), severity, lint.rule, lint.message)
+}
+
+// the functions and builtins called from roots, directly or through other functions
+fn called_functions(defs: &[Lang], roots: &[&Expr]) -> HashSet<String> {
+    let mut pending: Vec<String> = Vec::new();
+    for root in roots {
+        calls_in(root, &mut pending);
+    }
+    let mut called: HashSet<String> = HashSet::new();
+    while let Some(fun) = pending.pop() {
+        if called.insert(fun.clone()) {
+            for piece in defs {
+                if let Lang::Def(Def::Func(name, _, _, body)) = piece {
+                    if *name == fun {
+                        calls_in(body, &mut pending);
+                    }
+                }
             }
         }
     }
+    called
+}
+
+// whether evaluating e always reaches a break of the enclosing loop
+fn always_breaks(e: &Expr) -> bool {
+    match e {
+        Expr::Break(_) => true,
+        // a break inside a nested loop only leaves that loop, and a function body has no loop around it
+        Expr::Loop(_) => false,
+        Expr::If(cond, then, other) => always_breaks(cond) || (always_breaks(then) && always_breaks(other)),
+        Expr::Match(e, _) => always_breaks(e),
+        _ => sub_exprs(e).into_iter().any(always_breaks),
+    }
+}
+
+// whether a loop body has a break for that loop, breaks of nested loops don't count
+fn has_break(e: &Expr) -> bool {
+    match e {
+        Expr::Break(_) => true,
+        Expr::Loop(_) => false,
+        _ => sub_exprs(e).into_iter().any(has_break),
+    }
+}
+
+// an expression without effects, it can only be there for its value
+fn is_pure(e: &Expr) -> bool {
+    match e {
+        Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_) | Expr::Id(_) | Expr::Nil => true,
+        Expr::UnOp(_, _) | Expr::BinOp(_, _, _) | Expr::If(_, _, _) | Expr::Tuple(_) | Expr::Pos(_, _) => {
+            sub_exprs(e).into_iter().all(is_pure)
+        }
+        _ => false,
+    }
+}
+
+// a value that is surely a tuple or struct, which = compares by identity
+fn is_heap_value(e: &Expr, structs: &[StructDef]) -> bool {
+    match e {
+        Expr::Pos(_, e) => is_heap_value(e, structs),
+        Expr::Tuple(_) | Expr::MakeVector(_, _) => true,
+        Expr::Call(name, _) => structs.iter().any(|s| s.name == *name),
+        _ => false,
+    }
+}
+
+fn pattern_names(pattern: &Pattern, names: &mut Vec<String>) {
+    match pattern {
+        Pattern::Var(name) => names.push(name.clone()),
+        Pattern::Tuple(ps) | Pattern::Variant(_, ps) => {
+            for p in ps {
+                pattern_names(p, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+// the rules that look at one expression at a time. params are those of the function
+// e is part of, pos the position of the innermost list around e
+fn lint_expr(e: &Expr, params: &[String], structs: &[StructDef], pos: Option<&Pos>, lints: &mut Vec<Lint>) {
+    let pos = match e {
+        Expr::Pos(p, _) => Some(p),
+        _ => pos,
+    };
+    match e {
+        Expr::Block(es) => {
+            if let Some(i) = es[..es.len().saturating_sub(1)].iter().position(always_breaks) {
+                let at = match &es[i + 1] {
+                    Expr::Pos(p, _) => Some(p),
+                    _ => pos,
+                };
+                lints.push(lint("unreachable-code", at, "unreachable code after break".to_string()));
+            }
+            for e in &es[..es.len().saturating_sub(1)] {
+                if is_pure(e) {
+                    let at = match e {
+                        Expr::Pos(p, _) => Some(p),
+                        _ => pos,
+                    };
+                    lints.push(lint("pure-expression", at, "the value of this expression is discarded and it has no effect".to_string()));
+                }
+            }
+        }
+        Expr::Loop(body) if !has_break(body) => {
+            lints.push(lint("infinite-loop", pos, "loop without a break never ends".to_string()));
+        }
+        Expr::Let(binds, _) => {
+            for (name, _) in binds {
+                if params.contains(name) {
+                    lints.push(lint("shadowed-parameter", pos, format!("let binding {} shadows a parameter", name)));
+                }
+            }
+        }
+        Expr::BinOp(Op2::Equal, e1, e2) if is_heap_value(e1, structs) || is_heap_value(e2, structs) => {
+            lints.push(lint("tuple-equality", pos, "= compares tuples by identity, use equal to compare their contents".to_string()));
+        }
+        _ => {}
+    }
+    for sub in sub_exprs(e) {
+        lint_expr(sub, params, structs, pos, lints);
+    }
+}
+
+// the variables read before they are next assigned, going backwards from out (the ones
+// live after e). brk is what is live after the enclosing loop. With a list of lints this
+// also reports set! of variables that are never read afterwards
+fn live_vars(
+    e: &Expr,
+    out: HashSet<String>,
+    brk: &HashSet<String>,
+    pos: Option<&Pos>,
+    lints: &mut Option<&mut Vec<Lint>>,
+) -> HashSet<String> {
+    let pos = match e {
+        Expr::Pos(p, _) => Some(p),
+        _ => pos,
+    };
+    match e {
+        Expr::Id(name) => {
+            let mut live = out;
+            live.insert(name.clone());
+            live
+        }
+        Expr::Set(name, value) => {
+            if !out.contains(name) {
+                if let Some(lints) = lints {
+                    lints.push(lint("dead-set", pos, format!("{} is set but never read afterwards", name)));
+                }
+            }
+            let mut live = out;
+            live.remove(name);
+            live_vars(value, live, brk, pos, lints)
+        }
+        Expr::Let(binds, body) => {
+            let mut live = live_vars(body, out.clone(), brk, pos, lints);
+            for (name, value) in binds.iter().rev() {
+                // a variable of the same name from outside is untouched by the let
+                if !out.contains(name) {
+                    live.remove(name);
+                }
+                live = live_vars(value, live, brk, pos, lints);
+            }
+            live
+        }
+        Expr::If(cond, then, other) => {
+            let mut live = live_vars(then, out.clone(), brk, pos, lints);
+            live.extend(live_vars(other, out, brk, pos, lints));
+            live_vars(cond, live, brk, pos, lints)
+        }
+        Expr::Break(value) => live_vars(value, brk.clone(), brk, pos, lints),
+        Expr::Loop(body) => {
+            // the body runs again after itself, so go around until nothing changes
+            let mut live: HashSet<String> = HashSet::new();
+            loop {
+                let next = live_vars(body, live.clone(), &out, pos, &mut None);
+                if next == live {
+                    break;
+                }
+                live = next;
+            }
+            live_vars(body, live, &out, pos, lints)
+        }
+        Expr::Match(scrutinee, arms) => {
+            // an arm that fails falls through to the next one
+            let mut live = out.clone();
+            for arm in arms.iter().rev() {
+                let mut arm_live = live_vars(&arm.body, out.clone(), brk, pos, lints);
+                if let Some(guard) = &arm.guard {
+                    arm_live.extend(live.iter().cloned());
+                    arm_live = live_vars(guard, arm_live, brk, pos, lints);
+                }
+                let mut names = Vec::new();
+                pattern_names(&arm.pattern, &mut names);
+                for name in names {
+                    if !out.contains(&name) {
+                        arm_live.remove(&name);
+                    }
+                }
+                live.extend(arm_live);
+            }
+            live_vars(scrutinee, live, brk, pos, lints)
+        }
+        // everything else evaluates its parts from left to right
+        _ => sub_exprs(e).into_iter().rev().fold(out, |live, sub| live_vars(sub, live, brk, pos, lints)),
+    }
+}
+
+// check --lint: functions nothing calls, functions main never reaches, groups of functions
+// that call each other and the rules of lint_expr and live_vars in every body.
+// disabled lists the rules each file turned off
+fn lint_program(
+    defs: &[Lang],
+    main: &Lang,
+    fun_positions: &HashMap<String, Pos>,
+    structs: &[StructDef],
+    disabled: &HashMap<String, Vec<String>>,
+) -> Vec<Lint> {
+    let mut lints: Vec<Lint> = Vec::new();
+    let mut graph: Vec<(&String, Vec<String>)> = Vec::new();
+    for def in defs {
+        if let Lang::Def(Def::Func(name, _, _, body)) = def {
+            let mut calls = Vec::new();
+            calls_in(body, &mut calls);
+            graph.push((name, calls));
+        }
+    }
+    let main_calls = match main {
+        Lang::Expr(e) => {
+            let mut calls = Vec::new();
+            calls_in(e, &mut calls);
+            calls
+        }
+        Lang::Def(_) => Vec::new(),
+    };
+    let callees = |name: &String| graph.iter().find(|(f, _)| *f == name).map_or(&[][..], |(_, calls)| &calls[..]);
+    // every function reachable from a list of calls
+    let reach = |start: &[String]| -> Vec<String> {
+        let mut seen: Vec<String> = Vec::new();
+        let mut pending: Vec<String> = start.to_vec();
+        while let Some(f) = pending.pop() {
+            if graph.iter().any(|(g, _)| **g == f) && !seen.contains(&f) {
+                pending.extend(callees(&f).iter().cloned());
+                seen.push(f);
+            }
+        }
+        seen
+    };
+    let from_main = reach(&main_calls);
+    let mut in_cycle: Vec<&String> = Vec::new();
+    // the prelude is part of the call graph, but its functions are not the user's to fix
+    let is_prelude = |name: &String| name.starts_with("prelude.");
+    for (name, calls) in graph.iter().filter(|(name, _)| !is_prelude(name)) {
+        let at = fun_positions.get(*name);
+        let called = main_calls.contains(name) || graph.iter().any(|(f, calls)| f != name && calls.contains(name));
+        if !called {
+            lints.push(lint("unused-function", at, format!("function {} is never called", name)));
+        } else if !from_main.contains(name) {
+            lints.push(lint("unreachable-function", at, format!("function {} is never called from the main expression", name)));
+        }
+        // functions that reach each other are in the same cycle, reported once for the first of them
+        if reach(calls).contains(name) && !in_cycle.contains(name) {
+            let cycle: Vec<&String> = graph.iter()
+                .map(|(f, _)| *f)
+                .filter(|f| reach(calls).contains(f) && reach(callees(f)).contains(name))
+                .collect();
+            in_cycle.extend(cycle.iter().copied());
+            let names: Vec<&str> = cycle.iter().map(|f| f.as_str()).collect();
+            if names.len() == 1 {
+                lints.push(lint("recursion", at, format!("function {} is recursive", name)));
+            } else {
+                lints.push(lint("recursion", at, format!("functions {} are mutually recursive", names.join(", "))));
+            }
+        }
+    }
+    for piece in defs.iter().chain([main]) {
+        let (params, body, at) = match piece {
+            Lang::Def(Def::Func(name, _, _, _)) if is_prelude(name) => continue,
+            Lang::Def(Def::Func(name, params, rest, body)) => {
+                (params.iter().chain(rest).cloned().collect(), body.as_ref(), fun_positions.get(name))
+            }
+            Lang::Expr(e) => (Vec::new(), e, None),
+        };
+        lint_expr(body, &params, structs, at, &mut lints);
+        live_vars(body, HashSet::new(), &HashSet::new(), at, &mut Some(&mut lints));
+    }
+    lints.retain(|lint| match &lint.pos {
+        Some(pos) => !disabled.get(&pos.file).map_or(false, |rules| rules.iter().any(|rule| rule == lint.rule)),
+        None => true,
+    });
+    lints.sort_by_key(|lint| lint.pos.as_ref().map(|pos| (pos.file.clone(), pos.line, pos.col)));
+    lints
+}
+
+// (f a b c) for (fun (f x . rest) ...) becomes (f a (tuple b c)), and (f a) becomes (f a nil)
+fn pack_rest_args(e: &mut Expr, variadic: &HashMap<String, usize>) {
+    if let Expr::Call(name, args) = e {
+        if let Some(&required) = variadic.get(name) {
+            if args.len() < required {
+                panic!("Invalid, wrong number of parameters, {} takes at least {}", name, required)
+            }
+            let rest: Vec<Expr> = args.drain(required..).collect();
+            args.push(if rest.is_empty() { Expr::Nil } else { Expr::Tuple(rest) });
+        }
+    }
+    match e {
+        Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_) | Expr::Id(_) | Expr::Nil => {}
+        Expr::Let(binds, body) => {
+            for (_, bind) in binds.iter_mut() {
+                pack_rest_args(bind, variadic);
+            }
+            pack_rest_args(body, variadic);
+        }
+        Expr::UnOp(_, e) | Expr::Set(_, e) | Expr::Loop(e) | Expr::Break(e) | Expr::Pos(_, e) => {
+            pack_rest_args(e, variadic)
+        }
+        Expr::BinOp(_, e1, e2) | Expr::Index(e1, e2) | Expr::MakeVector(e1, e2) => {
+            pack_rest_args(e1, variadic);
+            pack_rest_args(e2, variadic);
+        }
+        Expr::If(e1, e2, e3) | Expr::SetTuple(e1, e2, e3) => {
+            pack_rest_args(e1, variadic);
+            pack_rest_args(e2, variadic);
+            pack_rest_args(e3, variadic);
+        }
+        Expr::Block(es) | Expr::Tuple(es) | Expr::Builtin(_, es) | Expr::Call(_, es) => {
+            for e in es.iter_mut() {
+                pack_rest_args(e, variadic);
+            }
+        }
+        Expr::Match(e, arms) => {
+            pack_rest_args(e, variadic);
+            for arm in arms.iter_mut() {
+                if let Some(guard) = &mut arm.guard {
+                    pack_rest_args(guard, variadic);
+                }
+                pack_rest_args(&mut arm.body, variadic);
+            }
+        }
+    }
+}
+
+// point calls and function values at the global names of the functions in scope, other
+// names are builtins. Names in bound are variables, which hide functions of the same name
+fn rename_calls(e: &mut Expr, scope: &HashMap<String, String>, bound: &HashSet<String>) {
+    match e {
+        Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_) | Expr::Nil => {}
+        Expr::Id(name) => {
+            if let (false, Some(global)) = (bound.contains(name), scope.get(name)) {
+                *name = global.clone();
+            }
+        }
+        Expr::Let(binds, body) => {
+            let mut bound = bound.clone();
+            for (name, bind) in binds.iter_mut() {
+                rename_calls(bind, scope, &bound);
+                bound.insert(name.clone());
+            }
+            rename_calls(body, scope, &bound);
+        }
+        Expr::UnOp(_, e) | Expr::Set(_, e) | Expr::Loop(e) | Expr::Break(e) | Expr::Pos(_, e) => {
+            rename_calls(e, scope, bound)
+        }
+        Expr::BinOp(_, e1, e2) | Expr::Index(e1, e2) | Expr::MakeVector(e1, e2) => {
+            rename_calls(e1, scope, bound);
+            rename_calls(e2, scope, bound);
+        }
+        Expr::If(e1, e2, e3) | Expr::SetTuple(e1, e2, e3) => {
+            rename_calls(e1, scope, bound);
+            rename_calls(e2, scope, bound);
+            rename_calls(e3, scope, bound);
+        }
+        Expr::Block(es) | Expr::Tuple(es) | Expr::Builtin(_, es) => {
+            for e in es.iter_mut() {
+                rename_calls(e, scope, bound);
+            }
+        }
+        Expr::Match(e, arms) => {
+            rename_calls(e, scope, bound);
+            for arm in arms.iter_mut() {
+                let mut names = Vec::new();
+                pattern_names(&arm.pattern, &mut names);
+                let mut bound = bound.clone();
+                bound.extend(names);
+                if let Some(guard) = &mut arm.guard {
+                    rename_calls(guard, scope, &bound);
+                }
+                rename_calls(&mut arm.body, scope, &bound);
+            }
+        }
+        Expr::Call(name, args) => {
+            if let (false, Some(global)) = (bound.contains(name), scope.get(name)) {
+                *name = global.clone();
+            }
+            for e in args.iter_mut() {
+                rename_calls(e, scope, bound);
+            }
+        }
+    }
+}
+
+fn parse_struct(name: &str, fields: &[Sexp]) -> StructDef {
+    if KEY_WORDS.contains(&name) {
+        panic!("Invalid struct name, {} is a keyword", name)
+    }
+    let mut names: Vec<String> = Vec::new();
+    for field in fields {
+        match field {
+            Sexp::Atom(S(field)) if !names.contains(field) => names.push(field.clone()),
+            _ => panic!("Invalid field {} of struct {}", field, name),
+        }
+    }
+    StructDef { name: name.to_string(), fields: names, data: None }
+}
+
+// (data tree (leaf) (node val left right)), every variant is a struct of its own
+fn parse_data(name: &str, variants: &[Sexp]) -> Vec<StructDef> {
+    if KEY_WORDS.contains(&name) {
+        panic!("Invalid data name, {} is a keyword", name)
+    }
+    if variants.is_empty() {
+        panic!("Invalid data {}, expected at least one variant", name)
+    }
+    variants
+        .iter()
+        .map(|variant| match variant {
+            Sexp::List(vec) => match &vec[..] {
+                [Sexp::Atom(S(variant)), fields @ ..] => StructDef {
+                    data: Some(name.to_string()),
+                    ..parse_struct(variant, fields)
+                },
+                _ => panic!("Invalid variant {} of data {}", variant, name),
+            },
+            _ => panic!("Invalid variant {} of data {}, expected (name field ...)", variant, name),
+        })
+        .collect()
+}
+
+// (struct node (val left)) gives node, node-val, node-left, set-node-val!, set-node-left! and node?
+fn struct_ops(structs: &[StructDef]) -> HashMap<String, StructOp> {
+    let mut ops: HashMap<String, StructOp> = HashMap::new();
+    for (id, def) in structs.iter().enumerate() {
+        let id = id as i64;
+        let mut add = |name: String, op: StructOp| {
+            if ops.contains_key(&name) {
+                panic!("Invalid, struct {} defines {} which is already defined", def.name, name)
+            }
+            ops.insert(name, op);
+        };
+        add(def.name.clone(), StructOp::New(id));
+        add(format!("{}?", def.name), StructOp::Is(id));
+        for (i, field) in def.fields.iter().enumerate() {
+            add(format!("{}-{}", def.name, field), StructOp::Get(id, i as i64));
+            add(format!("set-{}-{}!", def.name, field), StructOp::Set(id, i as i64));
+        }
+    }
+    ops
+}
+
+// how deeply macro uses may expand into further macro uses
+const MACRO_DEPTH_LIMIT: usize = 100;
+
+// an s-expression that remembers where its lists were read, macros work on these
+// so that code passed to a macro keeps its position
+#[derive(Clone, Debug)]
+enum Syntax {
+    Atom(Atom),
+    List(Vec<Syntax>, Option<Pos>),
+}
+
+fn to_syntax(sexp: &Sexp, pos: &Positions) -> Syntax {
+    match sexp {
+        Sexp::Atom(atom) => Syntax::Atom(atom.clone()),
+        Sexp::List(items) => Syntax::List(
+            items.iter().map(|item| to_syntax(item, pos)).collect(),
+            pos.get(&(sexp as *const Sexp)).cloned(),
+        ),
+    }
+}
+
+fn to_sexp(syntax: &Syntax) -> Sexp {
+    match syntax {
+        Syntax::Atom(atom) => Sexp::Atom(atom.clone()),
+        Syntax::List(items, _) => Sexp::List(items.iter().map(to_sexp).collect()),
+    }
+}
+
+// record the positions of syntax for the sexp made from it, which must not move afterwards
+fn syntax_positions(syntax: &Syntax, sexp: &Sexp, positions: &mut Positions) {
+    if let (Syntax::List(items, pos), Sexp::List(sexps)) = (syntax, sexp) {
+        if let Some(pos) = pos {
+            positions.insert(sexp as *const Sexp, pos.clone());
+        }
+        for (item, sexp) in items.iter().zip(sexps) {
+            syntax_positions(item, sexp, positions);
+        }
+    }
+}
+
+fn symbol(syntax: &Syntax) -> Option<&str> {
+    match syntax {
+        Syntax::Atom(S(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn pos_prefix(pos: &Option<Pos>) -> String {
+    match pos {
+        Some(pos) => format!("{}:{}:{}: ", pos.file, pos.line, pos.col),
+        None => String::new(),
+    }
+}
+
+// (define-syntax name (syntax-rules (literal ...) (pattern template) ...))
+#[derive(Clone)]
+struct Macro {
+    name: String,
+    literals: Vec<String>,
+    rules: Vec<(Syntax, Syntax)>,
+}
+
+// what a pattern variable matched, a sequence for variables under ...
+#[derive(Clone, Debug)]
+enum Binding {
+    One(Syntax),
+    Seq(Vec<Binding>),
+}
+
+fn parse_macro(form: &Syntax) -> Macro {
+    let (items, pos) = match form {
+        Syntax::List(items, pos) => (items, pos),
+        _ => unreachable!(),
+    };
+    let invalid = |what: &str| -> ! { panic!("{}Invalid define-syntax, {}", pos_prefix(pos), what) };
+    let name = match items.get(1).and_then(symbol) {
+        Some(name) => name.to_string(),
+        None => invalid("expected a macro name"),
+    };
+    if KEY_WORDS.contains(&name.as_str()) {
+        invalid(&format!("{} is a keyword", name));
+    }
+    let rules = match items.get(2) {
+        Some(Syntax::List(rules, _)) if items.len() == 3 && rules.first().and_then(symbol) == Some("syntax-rules") => rules,
+        _ => invalid("expected (syntax-rules (literal ...) (pattern template) ...)"),
+    };
+    let literals = match rules.get(1) {
+        Some(Syntax::List(literals, _)) => literals
+            .iter()
+            .map(|l| symbol(l).unwrap_or_else(|| invalid("literals must be names")).to_string())
+            .collect(),
+        _ => invalid("expected a list of literals after syntax-rules"),
+    };
+    let rules = rules[2..]
+        .iter()
+        .map(|rule| match rule {
+            Syntax::List(parts, _) if parts.len() == 2 && matches!(parts[0], Syntax::List(..)) => {
+                (parts[0].clone(), parts[1].clone())
+            }
+            _ => invalid("each rule must be (pattern template)"),
+        })
+        .collect();
+    Macro { name, literals, rules }
+}
+
+fn is_ellipsis(syntax: &Syntax) -> bool {
+    symbol(syntax) == Some("...")
+}
+
+// names bound by a pattern
+fn pattern_vars(pattern: &Syntax, literals: &[String], vars: &mut Vec<String>) {
+    match pattern {
+        Syntax::Atom(S(name)) if name != "_" && name != "..." && !literals.contains(name) => {
+            vars.push(name.clone())
+        }
+        Syntax::Atom(_) => {}
+        Syntax::List(items, _) => {
+            for item in items {
+                pattern_vars(item, literals, vars);
+            }
+        }
+    }
+}
+
+fn match_pattern(
+    pattern: &Syntax,
+    form: &Syntax,
+    literals: &[String],
+    binds: &mut HashMap<String, Binding>,
+) -> bool {
+    match pattern {
+        Syntax::Atom(S(name)) if name == "_" => true,
+        Syntax::Atom(S(name)) if literals.contains(name) => symbol(form) == Some(name.as_str()),
+        Syntax::Atom(S(name)) => {
+            binds.insert(name.clone(), Binding::One(form.clone()));
+            true
+        }
+        Syntax::Atom(atom) => matches!(form, Syntax::Atom(other) if other == atom),
+        Syntax::List(pats, _) => {
+            let forms = match form {
+                Syntax::List(forms, _) => forms,
+                _ => return false,
+            };
+            match pats.iter().position(is_ellipsis) {
+                None => {
+                    pats.len() == forms.len()
+                        && pats.iter().zip(forms).all(|(p, f)| match_pattern(p, f, literals, binds))
+                }
+                Some(0) => false,
+                Some(e) => {
+                    // p ... matches as many forms as the patterns after it leave over
+                    let (before, repeat, after) = (&pats[..e - 1], &pats[e - 1], &pats[e + 1..]);
+                    if forms.len() < before.len() + after.len() {
+                        return false;
+                    }
+                    let rest = forms.len() - after.len();
+                    if !before.iter().zip(forms).all(|(p, f)| match_pattern(p, f, literals, binds)) {
+                        return false;
+                    }
+                    let mut vars = Vec::new();
+                    pattern_vars(repeat, literals, &mut vars);
+                    let mut seqs: Vec<Vec<Binding>> = vec![Vec::new(); vars.len()];
+                    for f in &forms[before.len()..rest] {
+                        let mut inner = HashMap::new();
+                        if !match_pattern(repeat, f, literals, &mut inner) {
+                            return false;
+                        }
+                        for (seq, var) in seqs.iter_mut().zip(&vars) {
+                            seq.push(inner.get(var).unwrap().clone());
+                        }
+                    }
+                    for (var, seq) in vars.into_iter().zip(seqs) {
+                        binds.insert(var, Binding::Seq(seq));
+                    }
+                    after.iter().zip(&forms[rest..]).all(|(p, f)| match_pattern(p, f, literals, binds))
+                }
+            }
+        }
+    }
+}
+
+// names a template binds with let itself, renamed on every expansion so they can't
+// capture names from the code passed to the macro
+fn template_binders(template: &Syntax, binds: &HashMap<String, Binding>, binders: &mut Vec<String>) {
+    if let Syntax::List(items, _) = template {
+        if let [Syntax::Atom(S(op)), Syntax::List(bindings, _), ..] = &items[..] {
+            if op == "let" || op == "let*" {
+                for binding in bindings {
+                    if let Syntax::List(pair, _) = binding {
+                        if let Some(name) = pair.first().and_then(symbol) {
+                            if !binds.contains_key(name) && !binders.iter().any(|b| b == name) {
+                                binders.push(name.to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for item in items {
+            template_binders(item, binds, binders);
+        }
+    }
+}
+
+// lists made by the template get the position of the macro use
+fn instantiate(
+    template: &Syntax,
+    binds: &HashMap<String, Binding>,
+    renames: &HashMap<String, String>,
+    pos: &Option<Pos>,
+) -> Syntax {
+    match template {
+        Syntax::Atom(S(name)) => match binds.get(name) {
+            Some(Binding::One(syntax)) => syntax.clone(),
+            Some(Binding::Seq(_)) => {
+                panic!("{}Invalid macro template, {} must be followed by ...", pos_prefix(pos), name)
+            }
+            None => match renames.get(name) {
+                Some(fresh) => Syntax::Atom(S(fresh.clone())),
+                None => template.clone(),
+            },
+        },
+        Syntax::Atom(_) => template.clone(),
+        Syntax::List(items, _) => {
+            let mut res = Vec::new();
+            let mut i = 0;
+            while i < items.len() {
+                if i + 1 < items.len() && is_ellipsis(&items[i + 1]) {
+                    let mut vars = Vec::new();
+                    pattern_vars(&items[i], &[], &mut vars);
+                    let seqs: Vec<(&String, &Vec<Binding>)> = vars
+                        .iter()
+                        .filter_map(|v| match binds.get(v) {
+                            Some(Binding::Seq(seq)) => Some((v, seq)),
+                            _ => None,
+                        })
+                        .collect();
+                    if seqs.is_empty() {
+                        panic!("{}Invalid macro template, nothing to repeat before ...", pos_prefix(pos));
+                    }
+                    let len = seqs[0].1.len();
+                    if seqs.iter().any(|(_, seq)| seq.len() != len) {
+                        panic!("{}Invalid macro use, sequences under ... have different lengths", pos_prefix(pos));
+                    }
+                    for j in 0..len {
+                        let mut inner = binds.clone();
+                        for (var, seq) in &seqs {
+                            inner.insert(var.to_string(), seq[j].clone());
+                        }
+                        res.push(instantiate(&items[i], &inner, renames, pos));
+                    }
+                    i += 2;
+                } else {
+                    res.push(instantiate(&items[i], binds, renames, pos));
+                    i += 1;
+                }
+            }
+            Syntax::List(res, pos.clone())
+        }
+    }
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    trace: bool,
+    fresh: usize,
+}
+
+impl Expander {
+    // function definitions only expand their body
+    fn expand_top(&mut self, form: Syntax) -> Syntax {
+        match form {
+            Syntax::List(mut items, pos) if items.len() == 3 && symbol(&items[0]) == Some("fun") => {
+                let body = items.pop().unwrap();
+                items.push(self.expand(body, 0));
+                Syntax::List(items, pos)
+            }
+            _ => self.expand(form, 0),
+        }
+    }
+
+    fn expand(&mut self, form: Syntax, depth: usize) -> Syntax {
+        let (items, pos) = match form {
+            Syntax::List(items, pos) => (items, pos),
+            atom => return atom,
+        };
+        let head = items.first().and_then(symbol).map(|s| s.to_string());
+        match head {
+            Some(name) if self.macros.contains_key(&name) => {
+                let form = Syntax::List(items, pos.clone());
+                if depth >= MACRO_DEPTH_LIMIT {
+                    panic!(
+                        "{}Invalid, expanding {} went deeper than {} macro uses, is it recursive without end?",
+                        pos_prefix(&pos),
+                        name,
+                        MACRO_DEPTH_LIMIT
+                    );
+                }
+                let expanded = self.expand_once(&name, &form, &pos);
+                if self.trace {
+                    eprintln!("{}{} => {}", pos_prefix(&pos), to_sexp(&form), to_sexp(&expanded));
+                }
+                self.expand(expanded, depth + 1)
+            }
+            // only the values of let bindings are expressions
+            Some(name) if (name == "let" || name == "let*") && items.len() == 3 => {
+                let mut items = items.into_iter();
+                let op = items.next().unwrap();
+                let bindings = match items.next().unwrap() {
+                    Syntax::List(bindings, bpos) => Syntax::List(
+                        bindings
+                            .into_iter()
+                            .map(|binding| match binding {
+                                Syntax::List(mut pair, ppos) if pair.len() == 2 => {
+                                    let value = pair.pop().unwrap();
+                                    pair.push(self.expand(value, depth));
+                                    Syntax::List(pair, ppos)
+                                }
+                                other => other,
+                            })
+                            .collect(),
+                        bpos,
+                    ),
+                    other => other,
+                };
+                let body = self.expand(items.next().unwrap(), depth);
+                Syntax::List(vec![op, bindings, body], pos)
+            }
+            _ => Syntax::List(items.into_iter().map(|item| self.expand(item, depth)).collect(), pos),
+        }
+    }
+
+    fn expand_once(&mut self, name: &str, form: &Syntax, pos: &Option<Pos>) -> Syntax {
+        let mac = self.macros.get(name).unwrap();
+        let args = match form {
+            Syntax::List(items, p) => Syntax::List(items[1..].to_vec(), p.clone()),
+            _ => unreachable!(),
+        };
+        for (pattern, template) in &mac.rules {
+            // the first element of a pattern stands for the macro name
+            let pattern = match pattern {
+                Syntax::List(pats, p) if !pats.is_empty() => Syntax::List(pats[1..].to_vec(), p.clone()),
+                _ => continue,
+            };
+            let mut binds = HashMap::new();
+            if match_pattern(&pattern, &args, &mac.literals, &mut binds) {
+                let mut binders = Vec::new();
+                template_binders(template, &binds, &mut binders);
+                self.fresh += 1;
+                let renames: HashMap<String, String> = binders
+                    .into_iter()
+                    .map(|b| (b.clone(), format!("{}%{}", b, self.fresh)))
+                    .collect();
+                return instantiate(template, &binds, &renames, pos);
+            }
+        }
+        panic!("{}Invalid use of macro {}, no rule matches {}", pos_prefix(pos), name, to_sexp(form))
+    }
+}
+
+fn parse_all(contents: Vec<&str>) -> Vec<Sexp> {
+    let mut res: Vec<Sexp> = Vec::new();
+    for piece in contents {
+        res.push(parse(piece).expect("Invalid parentheses"));
+    }
     return res;
 }
-fn parse_func(name: &Sexp, body: &Sexp) -> Def {
+
+// borrow the forms, positions are keyed by the address of each node
+fn parse_piece(segment: &Sexp, pos: &Positions) -> Lang {
+    match segment {
+        Sexp::List(vec) => match &vec[..] {
+            [Sexp::Atom(S(op)), e1, e2] if op == "fun" => Lang::Def(parse_func(e1, e2, pos)),
+            _ => Lang::Expr(parse_expr(segment, pos)),
+        },
+        _ => Lang::Expr(parse_expr(segment, pos)),
+    }
+}
+fn parse_func(name: &Sexp, body: &Sexp, pos: &Positions) -> Def {
     let mut params: Vec<String> = Vec::new();
     match name {
         Sexp::List(vec) => {
@@ -1109,10 +3634,84 @@ fn parse_func(name: &Sexp, body: &Sexp) -> Def {
         }
         _ => panic!("Invalid, parse name fail"),
     }
-    if params.len() == 0 {
+    if params.is_empty() {
       panic!("Invalid, no function name")
     }
     let func_name = params[0].clone();
     params.remove(0);
-    Def::Func(func_name.to_string(), params, Box::new(parse_expr(body)))
+    // extra arguments are packed into a tuple for the parameter after the dot
+    let rest = match params.iter().position(|p| p == ".") {
+        Some(i) if i + 2 == params.len() && params[i + 1] != "." => {
+            let rest = params.pop();
+            params.pop();
+            rest
+        }
+        Some(_) => panic!("Invalid, expected one rest parameter after . in {}", func_name),
+        None => None,
+    };
+    Def::Func(func_name.to_string(), params, rest, Box::new(parse_expr(body, pos)))
+}
+
+// line and column of every list in the program, keyed by the address of its node.
+// each form was read from the slice of text in pieces with the same index
+fn sexp_positions(file: &str, text: &str, pieces: &[&str], forms: &[Sexp]) -> Positions {
+    let mut positions: Positions = HashMap::new();
+    for (piece, form) in pieces.iter().zip(forms) {
+        let mut at = piece.as_ptr() as usize - text.as_ptr() as usize;
+        record_positions(form, file, text, &mut at, &mut positions);
+    }
+    positions
+}
+
+// walk the text the same way sexp reads it, at is left right after sexp
+fn record_positions(sexp: &Sexp, file: &str, text: &str, at: &mut usize, positions: &mut Positions) {
+    skip_space(text, at);
+    match sexp {
+        Sexp::List(items) => {
+            let before = &text[..*at];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let pos = Pos {
+                file: file.to_string(),
+                line: before.matches('\n').count() + 1,
+                col: before[line_start..].chars().count() + 1,
+            };
+            positions.insert(sexp as *const Sexp, pos);
+            // the opening parenthesis
+            *at += 1;
+            for item in items {
+                record_positions(item, file, text, at, positions);
+            }
+            skip_space(text, at);
+            *at += 1;
+        }
+        Sexp::Atom(_) => {
+            let rest = &text[*at..];
+            let len = if let Some(inner) = rest.strip_prefix('"') {
+                let mut escaped = false;
+                let close = inner.char_indices().find(|(_, c)| {
+                    let found = *c == '"' && !escaped;
+                    escaped = *c == '\\' && !escaped;
+                    found
+                });
+                close.map_or(rest.len(), |(i, _)| i + 2)
+            } else {
+                rest.find(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == ';')
+                    .unwrap_or(rest.len())
+            };
+            *at += len;
+        }
+    }
+}
+
+// whitespace and ; comments
+fn skip_space(text: &str, at: &mut usize) {
+    loop {
+        let rest = &text[*at..];
+        let trimmed = rest.trim_start();
+        *at += rest.len() - trimmed.len();
+        if !trimmed.starts_with(';') {
+            return;
+        }
+        *at += trimmed.find('\n').unwrap_or(trimmed.len());
+    }
 }